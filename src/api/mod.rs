@@ -1,9 +1,12 @@
 use serde::Serialize;
 use crate::blockchain::Blockchain;
 use crate::generator::generator::TransactionGenerator;
-use crate::types::state::{State, StatePerBlock};
-use crate::types::hash::H256;
-use crate::miner::Handle as MinerHandle;
+use crate::types::state::StatePerBlock;
+use crate::types::mempool::{Mempool, MempoolStats};
+use crate::types::hash::{H256, Hashable};
+use crate::miner::{Handle as MinerHandle, OperatingState};
+use crate::miner::external::ExternalMining;
+use crate::types::block::Header as BlockHeader;
 use crate::network::server::Handle as NetworkServerHandle;
 use crate::network::message::Message;
 
@@ -18,11 +21,19 @@ use url::Url;
 
 pub struct Server {
     handle: HTTPServer,
-    miner: MinerHandle,
-    network: NetworkServerHandle,
-    blockchain: Arc<Mutex<Blockchain>>,
-    tx_generator: TransactionGenerator,
-    state_per_block: Arc<Mutex<StatePerBlock>>,
+    context: Context,
+}
+
+/// Handles to the parts of the node the API serves, every request gets its own copy
+#[derive(Clone)]
+pub struct Context {
+    pub miner: MinerHandle,
+    pub network: NetworkServerHandle,
+    pub blockchain: Arc<Mutex<Blockchain>>,
+    pub tx_generator: TransactionGenerator,
+    pub state_per_block: Arc<Mutex<StatePerBlock>>,
+    pub mempool: Arc<Mutex<Mempool>>,
+    pub external_mining: ExternalMining,
 }
 
 #[derive(Serialize)]
@@ -31,6 +42,39 @@ struct ApiResponse {
     message: String,
 }
 
+#[derive(Serialize)]
+struct MempoolInfo {
+    transactions: usize,
+    bytes: usize,
+    ready: usize,
+    future: usize,
+    #[serde(flatten)]
+    stats: MempoolStats,
+}
+
+#[derive(Serialize)]
+struct MinerInfo {
+    state: &'static str,
+    lambda: Option<u64>,
+    template_parent: Option<String>,
+    hashes: u64,
+    blocks_found: u64,
+    hashrate: f64,
+}
+
+/// Work for an external miner. `header` is the serialized header, with the nonce in bytes 32 to 36
+/// (little endian); its hash must not exceed `difficulty`.
+#[derive(Serialize)]
+struct BlockTemplate {
+    parent: String,
+    difficulty: String,
+    timestamp: u128,
+    merkle_root: String,
+    header: String,
+    /// Serialized transactions, the coinbase first
+    transactions: Vec<String>,
+}
+
 macro_rules! respond_result {
     ( $req:expr, $success:expr, $message:expr ) => {{
         let content_type = "Content-Type: application/json".parse::<Header>().unwrap();
@@ -53,30 +97,20 @@ macro_rules! respond_json {
 }
 
 impl Server {
-    pub fn start(
-        addr: std::net::SocketAddr,
-        miner: &MinerHandle,
-        network: &NetworkServerHandle,
-        blockchain: &Arc<Mutex<Blockchain>>,
-        tx_generator: &TransactionGenerator,
-        state_per_block: &Arc<Mutex<StatePerBlock>>,
-    ) {
+    pub fn start(addr: std::net::SocketAddr, context: Context) {
         let handle = HTTPServer::http(&addr).unwrap();
-        let server = Self {
-            handle,
-            miner: miner.clone(),
-            network: network.clone(),
-            blockchain: Arc::clone(blockchain),
-            tx_generator: tx_generator.clone(),
-            state_per_block: Arc::clone(state_per_block),
-        };
+        let server = Self { handle, context };
         thread::spawn(move || {
             for req in server.handle.incoming_requests() {
-                let miner = server.miner.clone();
-                let network = server.network.clone();
-                let blockchain = Arc::clone(&server.blockchain);
-                let tx_generator = server.tx_generator.clone();
-                let state_per_block = Arc::clone(&server.state_per_block);
+                let Context {
+                    miner,
+                    network,
+                    blockchain,
+                    tx_generator,
+                    state_per_block,
+                    mempool,
+                    external_mining,
+                } = server.context.clone();
                 thread::spawn(move || {
                     // a valid url requires a base
                     let base_url = Url::parse(&format!("http://{}/", &addr)).unwrap();
@@ -112,6 +146,76 @@ impl Server {
                             miner.start(lambda);
                             respond_result!(req, true, "ok");
                         }
+                        "/miner/stop" => {
+                            miner.pause();
+                            respond_result!(req, true, "ok");
+                        }
+                        "/miner/status" => {
+                            let status = miner.status();
+                            let (state, lambda) = match status.state {
+                                OperatingState::Paused => ("paused", None),
+                                OperatingState::Run(lambda) => ("running", Some(lambda)),
+                                OperatingState::ShutDown => ("shut down", None),
+                            };
+                            let info = MinerInfo {
+                                state,
+                                lambda,
+                                template_parent: status.template_parent.map(|hash| hash.to_string()),
+                                hashes: status.hashes,
+                                blocks_found: status.blocks_found,
+                                hashrate: status.hashrate,
+                            };
+                            respond_json!(req, info);
+                        }
+                        "/mining/template" => {
+                            let block = external_mining.template();
+                            let template = BlockTemplate {
+                                parent: block.header.parent.to_string(),
+                                difficulty: block.header.difficulty.to_string(),
+                                timestamp: block.header.timestamp,
+                                merkle_root: block.header.merkle_root.to_string(),
+                                header: hex::encode(bincode::serialize(&block.header).unwrap()),
+                                transactions: block
+                                    .content
+                                    .transactions
+                                    .iter()
+                                    .map(|tx| hex::encode(bincode::serialize(tx).unwrap()))
+                                    .collect(),
+                            };
+                            respond_json!(req, template);
+                        }
+                        "/mining/submit" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let (header, nonce) = match (params.get("header"), params.get("nonce")) {
+                                (Some(header), Some(nonce)) => (header, nonce),
+                                _ => {
+                                    respond_result!(req, false, "missing header or nonce");
+                                    return;
+                                }
+                            };
+                            let header = match hex::decode(header)
+                                .map_err(|e| e.to_string())
+                                .and_then(|bytes| bincode::deserialize::<BlockHeader>(&bytes).map_err(|e| e.to_string()))
+                            {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, format!("error parsing header: {}", e));
+                                    return;
+                                }
+                            };
+                            let nonce = match nonce.parse::<u32>() {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, format!("error parsing nonce: {}", e));
+                                    return;
+                                }
+                            };
+                            match external_mining.submit(BlockHeader { nonce, ..header }) {
+                                Ok(hash) => respond_result!(req, true, hash),
+                                Err(e) => respond_result!(req, false, e),
+                            }
+                        }
                         "/tx-generator/start" => {
                             let params = url.query_pairs();
                             let params: HashMap<_, _> = params.into_owned().collect();
@@ -177,24 +281,28 @@ impl Server {
                                     return;
                                 }
                             };
-                            let mut longest_chain = Vec::new();
-                            let mut block_hash = H256::default();
-                            let mut block_state = State::new();
-                            {
-                                let blockchain = blockchain.lock().unwrap();
-                                longest_chain = blockchain.all_blocks_in_longest_chain();
-                                assert!(longest_chain.len() > block_id);
-                                block_hash = longest_chain[block_id];
-                            }
+                            let block_hash = match blockchain.lock().unwrap().block_at_height(block_id) {
+                                Some(block) => block.hash(),
+                                None => {
+                                    respond_result!(req, false, format!("no block at height {}", block_id));
+                                    return;
+                                }
+                            };
 
-                            let mut v_string = Vec::new();
-                            {
-                                let state_per_block = state_per_block.lock().unwrap();
-                                block_state = state_per_block.get_state(&block_hash);
-                                v_string = block_state.to_vec_string();
-                            }
+                            let v_string = state_per_block.lock().unwrap().get_state(&block_hash).to_vec_string();
                             respond_json!(req, v_string);
                         }
+                        "/mempool/stats" => {
+                            let mempool = mempool.lock().unwrap();
+                            let info = MempoolInfo {
+                                transactions: mempool.len(),
+                                bytes: mempool.bytes(),
+                                ready: mempool.ready_len(),
+                                future: mempool.future_len(),
+                                stats: mempool.stats().clone(),
+                            };
+                            respond_json!(req, info);
+                        }
                         "/blockchain/longest-chain-tx-count" => {
                             // unimplemented!()
                             respond_result!(req, false, "unimplemented!");
@@ -1,32 +1,73 @@
+pub mod store;
+
 use std::collections::HashMap;
+use std::io;
 use std::sync::{Arc, Mutex};
 use crate::types::block::{Block, Content, Header};
 use crate::types::hash::H256;
 use crate::types::merkle::MerkleTree; // Make sure to include the MerkleTree
 use crate::types::hash::Hashable; 
+use crate::types::params::{ChainParams, RetargetRule};
 use std::time::SystemTime;
-use hex_literal::hex;
+use log::warn;
+use self::store::{BlockStore, MemoryStore};
+
 
+/// Number of blocks whose median timestamp a new block must exceed
+pub const MEDIAN_TIME_SPAN: usize = 11;
+
+/// How the main chain changed when an inserted block moved the tip
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reorg {
+    /// Last block shared by the old and the new main chain
+    pub common_ancestor: H256,
+    /// Blocks that left the main chain, from the old tip back to the common ancestor
+    pub disconnected: Vec<H256>,
+    /// Blocks that joined the main chain, from the common ancestor up to the new tip
+    pub connected: Vec<H256>,
+}
 
 pub struct Blockchain {
     blocks: HashMap<H256, Block>, // Storing blocks by their hash
-    tip: H256, // The hash of the latest block in the longest chain
+    tip: H256, // The hash of the latest block in the chain with the most work
     heights: HashMap<H256, usize>, // Mapping of block hashes to their heights
+    main_chain: Vec<H256>, // Hashes of the heaviest chain, indexed by height
+    total_work: HashMap<H256, H256>, // Cumulative proof-of-work from genesis up to each block
+    store: Box<dyn BlockStore>, // Persistent backend, every inserted block is written here
+    params: ChainParams,
 }
 
 impl Blockchain {
     /// Create a new blockchain, only containing the genesis block
     pub fn new() -> Self {
+        Self::with_genesis(ChainParams::default(), Box::new(MemoryStore))
+    }
+
+    /// Open a blockchain backed by `store`, reloading every block it already holds
+    pub fn open(params: ChainParams, mut store: Box<dyn BlockStore>) -> io::Result<Self> {
+        let stored_blocks = store.load()?;
+        let mut blockchain = Self::with_genesis(params, store);
+        for block in stored_blocks.iter() {
+            if !blockchain.exist(&block.get_parent()) {
+                warn!("Skipping stored block {} with unknown parent", block.hash());
+                continue;
+            }
+            blockchain.insert_in_memory(block);
+        }
+        Ok(blockchain)
+    }
+
+    fn with_genesis(params: ChainParams, store: Box<dyn BlockStore>) -> Self {
         // Create the genesis block with fixed values
         // println!("New Blockchain");
-        let difficulty = hex!("000fffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff").into(); // set difficulty
+        let difficulty = params.initial_target; // set difficulty
         let genesis_block = Block {
             header: Header {
                 parent: H256::from([0x00; 32]), // Genesis block has no parent
                 nonce: 0,
                 difficulty: difficulty, // Example fixed difficulty
-                timestamp: 0,
-                merkle_root: H256::from([0x00; 32]), // Placeholder for merkle root
+                timestamp: params.genesis_timestamp,
+                merkle_root: params.hash(), // No transactions, commit to the chain parameters instead
             },
             content: Content{
                         transactions: Vec::new(),}, // Use the public constructor
@@ -49,67 +90,90 @@ impl Blockchain {
                 heights_map.insert(genesis_hash, 0); // Store height of the genesis block
                 heights_map
             },
+            main_chain: vec![genesis_hash],
+            total_work: {
+                let mut work_map = HashMap::new();
+                work_map.insert(genesis_hash, difficulty.work());
+                work_map
+            },
+            store,
+            params,
         }
     }
 
-    /// Insert a block into blockchain
-    pub fn insert(&mut self, block: &Block) {
+    /// Insert a block into blockchain, returns how the main chain changed if the tip moved.
+    /// Fails if the block could not be persisted, in which case it is not inserted either.
+    pub fn insert(&mut self, block: &Block) -> io::Result<Option<Reorg>> {
         // println!("Insert Block");
+        if self.exist(&block.hash()) || !self.exist(&block.get_parent()) {
+            return Ok(None);
+        }
+        // write to disk first, so that memory never holds a block the store has lost
+        self.store.put(block)?;
+        Ok(self.insert_in_memory(block))
+    }
+
+    /// Index a block whose parent is known in memory, and move the tip if needed
+    fn insert_in_memory(&mut self, block: &Block) -> Option<Reorg> {
         let block_hash = block.hash(); // Calculate the hash of the new block
 
         // Determine the height of the new block
-        let new_height = match self.heights.get(&block.get_parent()) {
-            Some(&parent_height) => parent_height + 1, // Increment parent's height
-            None => return, // Handle invalid parent case
-        };
+        let new_height = self.heights[&block.get_parent()] + 1;
+        let new_work = self.total_work[&block.get_parent()].saturating_add(&block.get_difficulty().work());
 
         // Add the block to the blockchain
         self.blocks.insert(block_hash.clone(), block.clone());
         self.heights.insert(block_hash.clone(), new_height); // Store the new block's height
+        self.total_work.insert(block_hash, new_work);
 
-        // Update the tip if this block extends the longest chain
-        if new_height > self.heights[&self.tip] {
+        // Update the tip if this block extends the heaviest chain,
+        // ties are broken towards the smaller hash so that every node picks the same tip
+        let tip_work = self.total_work[&self.tip];
+        if new_work > tip_work || (new_work == tip_work && block_hash < self.tip) {
+            let reorg = self.reorg_to(&block_hash);
+            self.main_chain.truncate(self.heights[&reorg.common_ancestor] + 1);
+            self.main_chain.extend_from_slice(&reorg.connected);
             self.tip = block_hash;
+            return Some(reorg);
         }
+        None
     }
 
-    /// Get the last block's hash of the longest chain
+    /// Describe the switch of the main chain from the current tip to `new_tip`
+    fn reorg_to(&self, new_tip: &H256) -> Reorg {
+        let common_ancestor = self.common_ancestor(&self.tip, new_tip).unwrap();
+        let ancestor_height = self.heights[&common_ancestor];
+        let disconnected = self.main_chain[ancestor_height + 1..].iter().rev().cloned().collect();
+        let mut connected = Vec::new();
+        let mut hash = *new_tip;
+        while hash != common_ancestor {
+            connected.push(hash);
+            hash = self.blocks[&hash].get_parent();
+        }
+        connected.reverse();
+        Reorg { common_ancestor, disconnected, connected }
+    }
+
+    /// Get the last block's hash of the chain with the most cumulative work
     pub fn tip(&self) -> H256 {
         self.tip
     }
 
-    /// Get all blocks' hashes of the longest chain, ordered from genesis to the tip
-    pub fn all_blocks_in_longest_chain(&self) -> Vec<H256> {
-        // let mut hashes = Vec::new();
-        // let mut current_hash = self.tip;
-
-        // while let Some(hash) = self.blocks.get(&current_hash) {
-        //     hashes.push(current_hash);
-        //     current_hash = hash.get_parent();
-        // }
-
-        // hashes.reverse(); // Return in order from genesis to tip
-        // hashes
-        let mut new_vec: Vec<H256> = vec!();
-        let mut temp_hash = self.tip();
-        for i in 0..(self.heights[&self.tip]+1){
-            let temp_block = self.blocks.get(&temp_hash).unwrap();
-            let temp_block_copy = temp_block.clone();
-            let block_parent_hash = temp_block_copy.header.parent.clone();
+    /// Get the cumulative proof-of-work of the chain ending at `hash`
+    pub fn total_work(&self, hash: &H256) -> Option<H256> {
+        self.total_work.get(hash).copied()
+    }
 
-            new_vec.insert(0,temp_hash);
-            temp_hash = block_parent_hash;
-        }
-        new_vec
+    /// Get all blocks' hashes of the heaviest chain, ordered from genesis to the tip
+    pub fn all_blocks_in_longest_chain(&self) -> Vec<H256> {
+        self.main_chain.clone()
     }
 
     /// Get all transactions of the longest chain, ordered from genesis to the tip
     pub fn all_tx_in_longest_chain(&self) -> Vec<Vec<H256>> {
-        let longest_chain = self.all_blocks_in_longest_chain();
-
         let mut tx_vec = Vec::new();
-        for n in 0..longest_chain.len() {
-            let block = self.blocks.get(&longest_chain[n]).unwrap();
+        for hash in self.main_chain.iter() {
+            let block = &self.blocks[hash];
             let txs: Vec<H256> = block.get_transactions()
                                  .into_iter().map(|tx| tx.hash()).collect();
             tx_vec.push(txs);
@@ -121,9 +185,125 @@ impl Blockchain {
         self.blocks.contains_key(hash)
     }
 
-    pub fn get_block(&self, hash: &H256) -> Block {
-        return self.blocks.get(hash).unwrap().clone();
-    }  
+    pub fn get_block(&self, hash: &H256) -> Option<&Block> {
+        self.blocks.get(hash)
+    }
+
+    pub fn get_height(&self, hash: &H256) -> Option<usize> {
+        self.heights.get(hash).copied()
+    }
+
+    /// Get the main chain block at `height`
+    pub fn block_at_height(&self, height: usize) -> Option<&Block> {
+        self.main_chain.get(height).map(|hash| &self.blocks[hash])
+    }
+
+    pub fn is_on_main_chain(&self, hash: &H256) -> bool {
+        match self.heights.get(hash) {
+            Some(&height) => self.main_chain.get(height) == Some(hash),
+            None => false,
+        }
+    }
+
+    /// Get the last block shared by the chains ending at `a` and `b`
+    pub fn common_ancestor(&self, a: &H256, b: &H256) -> Option<H256> {
+        let (height_a, height_b) = (self.get_height(a)?, self.get_height(b)?);
+        let height = height_a.min(height_b);
+        let mut a = self.ancestor_at(a, height)?.hash();
+        let mut b = self.ancestor_at(b, height)?.hash();
+        while a != b {
+            a = self.blocks[&a].get_parent();
+            b = self.blocks[&b].get_parent();
+        }
+        Some(a)
+    }
+
+    pub fn params(&self) -> &ChainParams {
+        &self.params
+    }
+
+    /// Get the ancestor of `hash` at `height`, the block itself if `height` is its own height
+    pub fn ancestor_at(&self, hash: &H256, height: usize) -> Option<&Block> {
+        let mut block = self.blocks.get(hash)?;
+        if height > self.heights[hash] {
+            return None;
+        }
+        if self.is_on_main_chain(hash) {
+            return self.block_at_height(height);
+        }
+        for _ in height..self.heights[hash] {
+            block = &self.blocks[&block.get_parent()];
+        }
+        Some(block)
+    }
+
+    /// Get the median timestamp of `hash` and its ancestors, over at most `MEDIAN_TIME_SPAN` blocks
+    pub fn median_time_past(&self, hash: &H256) -> Option<u128> {
+        let mut timestamps = Vec::with_capacity(MEDIAN_TIME_SPAN);
+        let mut block = self.blocks.get(hash)?;
+        loop {
+            timestamps.push(block.header.timestamp);
+            if timestamps.len() == MEDIAN_TIME_SPAN || self.heights[&block.hash()] == 0 {
+                break;
+            }
+            block = &self.blocks[&block.get_parent()];
+        }
+        timestamps.sort();
+        Some(timestamps[timestamps.len() / 2])
+    }
+
+    /// Get the target a child of `parent` must be mined with, according to the retarget rule
+    pub fn next_difficulty(&self, parent: &H256) -> Option<H256> {
+        let parent_block = self.blocks.get(parent)?;
+        let parent_target = parent_block.get_difficulty();
+        let parent_height = self.heights[parent];
+        let (window, block_time) = match self.params.retarget {
+            RetargetRule::Fixed => return Some(parent_target),
+            RetargetRule::Interval { interval, block_time } => {
+                if !(parent_height + 1).is_multiple_of(interval) {
+                    return Some(parent_target);
+                }
+                (interval, block_time)
+            }
+            RetargetRule::MovingAverage { window, block_time } => (window, block_time),
+        };
+        let first_height = parent_height.saturating_sub(window);
+        let spans = (parent_height - first_height) as u64;
+        if spans == 0 {
+            return Some(parent_target);
+        }
+        let first_block = self.ancestor_at(parent, first_height)?;
+
+        // the target being rescaled: the parent's, or the average over the window
+        let base_target = match self.params.retarget {
+            RetargetRule::MovingAverage { .. } => {
+                let mut sum = H256::default();
+                let mut hash = *parent;
+                for _ in 0..spans {
+                    let block = &self.blocks[&hash];
+                    sum = sum.saturating_add(&block.get_difficulty().scale(1, spans));
+                    hash = block.get_parent();
+                }
+                sum
+            }
+            _ => parent_target,
+        };
+
+        // limit the adjustment to a factor of 4 either way, as Bitcoin does; the retarget rule
+        // keeps four times the expected time within a u64
+        let expected = spans as u128 * block_time as u128;
+        let actual = parent_block.header.timestamp.saturating_sub(first_block.header.timestamp);
+        let actual = actual.clamp(std::cmp::max(expected / 4, 1), expected * 4);
+        let target = base_target.scale(actual as u64, expected as u64);
+        Some(std::cmp::min(target, self.params.max_target))
+    }
+
+    /// Get every block except genesis, parents always before their children
+    pub fn all_blocks(&self) -> Vec<Block> {
+        let mut hashes: Vec<&H256> = self.heights.keys().filter(|hash| self.heights[*hash] > 0).collect();
+        hashes.sort_by_key(|hash| self.heights[*hash]);
+        hashes.into_iter().map(|hash| self.blocks[hash].clone()).collect()
+    }
 }
 
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
@@ -133,15 +313,289 @@ mod tests {
     use super::*;
     use crate::types::block::generate_random_block;
     use crate::types::hash::Hashable;
+    use crate::test_util::TempDir;
 
     #[test]
     fn insert_one() {
         let mut blockchain = Blockchain::new();
         let genesis_hash = blockchain.tip();
         let block = generate_random_block(&genesis_hash);
-        blockchain.insert(&block);
+        blockchain.insert(&block).unwrap();
+        assert_eq!(blockchain.tip(), block.hash());
+    }
+
+    #[test]
+    fn genesis_commits_to_params() {
+        let default = Blockchain::new().tip();
+        let premine = ChainParams { premine: Vec::new(), ..Default::default() };
+        assert_ne!(Blockchain::open(premine, Box::new(MemoryStore)).unwrap().tip(), default);
+        let reward = ChainParams { block_reward: 1, ..Default::default() };
+        assert_ne!(Blockchain::open(reward, Box::new(MemoryStore)).unwrap().tip(), default);
+        assert_eq!(Blockchain::open(ChainParams::default(), Box::new(MemoryStore)).unwrap().tip(), default);
+    }
+
+    #[test]
+    fn insert_reports_reorg() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let a1 = generate_random_block(&genesis_hash);
+        let reorg = blockchain.insert(&a1).unwrap().unwrap();
+        assert_eq!(reorg, Reorg { common_ancestor: genesis_hash, disconnected: vec![], connected: vec![a1.hash()] });
+        let a2 = block_with_target(&a1.hash(), hex!("7fffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff"));
+        blockchain.insert(&a2).unwrap();
+
+        // a side branch with less work leaves the tip alone
+        let b2 = generate_random_block(&a1.hash());
+        assert!(blockchain.insert(&b2).unwrap().is_none());
+        let b3 = block_with_target(&b2.hash(), hex!("0fffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff"));
+        let reorg = blockchain.insert(&b3).unwrap().unwrap();
+        assert_eq!(reorg, Reorg {
+            common_ancestor: a1.hash(),
+            disconnected: vec![a2.hash()],
+            connected: vec![b2.hash(), b3.hash()],
+        });
+        assert!(blockchain.insert(&b3).unwrap().is_none());
+    }
+
+    #[test]
+    fn height_index_follows_reorg() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let a1 = generate_random_block(&genesis_hash);
+        let a2 = generate_random_block(&a1.hash());
+        let b2 = block_with_target(&a1.hash(), hex!("0fffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff"));
+        let b3 = generate_random_block(&b2.hash());
+        blockchain.insert(&a1).unwrap();
+        blockchain.insert(&a2).unwrap();
+        assert_eq!(blockchain.block_at_height(2).unwrap().hash(), a2.hash());
+        assert!(blockchain.is_on_main_chain(&a2.hash()));
+
+        blockchain.insert(&b2).unwrap();
+        blockchain.insert(&b3).unwrap();
+        assert_eq!(blockchain.all_blocks_in_longest_chain(), vec![genesis_hash, a1.hash(), b2.hash(), b3.hash()]);
+        assert_eq!(blockchain.block_at_height(2).unwrap().hash(), b2.hash());
+        assert!(blockchain.block_at_height(4).is_none());
+        assert!(!blockchain.is_on_main_chain(&a2.hash()));
+        assert!(blockchain.is_on_main_chain(&b3.hash()));
+        assert!(!blockchain.is_on_main_chain(&H256::default()));
+
+        assert_eq!(blockchain.ancestor_at(&b3.hash(), 1).unwrap().hash(), a1.hash());
+        assert_eq!(blockchain.ancestor_at(&a2.hash(), 0).unwrap().hash(), genesis_hash);
+        assert_eq!(blockchain.ancestor_at(&a2.hash(), 2).unwrap().hash(), a2.hash());
+        assert!(blockchain.ancestor_at(&a2.hash(), 3).is_none());
+        assert_eq!(blockchain.common_ancestor(&a2.hash(), &b3.hash()), Some(a1.hash()));
+        assert_eq!(blockchain.common_ancestor(&b2.hash(), &b3.hash()), Some(b2.hash()));
+        assert_eq!(blockchain.common_ancestor(&a2.hash(), &H256::default()), None);
+        assert_eq!(blockchain.get_height(&b3.hash()), Some(3));
+        assert!(blockchain.get_block(&H256::default()).is_none());
+    }
+
+    #[test]
+    fn median_time_past() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        assert_eq!(blockchain.median_time_past(&genesis_hash), Some(0));
+        // timestamps 10, 20, .., 150 with one out-of-order block
+        let mut parent = genesis_hash;
+        for i in 1..=15u128 {
+            let mut block = generate_random_block(&parent);
+            block.header.timestamp = if i == 14 { 5 } else { i * 10 };
+            blockchain.insert(&block).unwrap();
+            parent = block.hash();
+            if i == 2 {
+                // median of 0, 10, 20
+                assert_eq!(blockchain.median_time_past(&parent), Some(10));
+            }
+        }
+        // median of 50, 60, .., 130, 5, 150
+        assert_eq!(blockchain.median_time_past(&parent), Some(90));
+        assert_eq!(blockchain.median_time_past(&H256::default()), None);
+    }
+
+    fn block_with_target(parent: &H256, target: [u8; 32]) -> Block {
+        let mut block = generate_random_block(parent);
+        block.header.difficulty = target.into();
+        block
+    }
+
+    #[test]
+    fn heavier_fork_wins_over_longer() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let easy = hex!("ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff");
+        let hard = hex!("0fffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff");
+
+        // a long chain of easy blocks, 5 units of work
+        let mut parent = genesis_hash;
+        for _ in 0..5 {
+            let block = block_with_target(&parent, easy);
+            blockchain.insert(&block).unwrap();
+            parent = block.hash();
+        }
+        assert_eq!(blockchain.tip(), parent);
+        let long_tip = parent;
+
+        // a single hard block carries 16 units of work
+        let heavy = block_with_target(&genesis_hash, hard);
+        blockchain.insert(&heavy).unwrap();
+        assert_eq!(blockchain.tip(), heavy.hash());
+        assert_eq!(blockchain.all_blocks_in_longest_chain(), vec![genesis_hash, heavy.hash()]);
+
+        // extending the light fork does not move the tip until it overtakes
+        let mut parent = long_tip;
+        for _ in 0..10 {
+            let block = block_with_target(&parent, easy);
+            blockchain.insert(&block).unwrap();
+            parent = block.hash();
+            assert_eq!(blockchain.tip(), heavy.hash());
+        }
+        // the 16th unit of work ties, the 17th overtakes
+        let block = block_with_target(&parent, easy);
+        blockchain.insert(&block).unwrap();
+        let block = block_with_target(&block.hash(), easy);
+        blockchain.insert(&block).unwrap();
         assert_eq!(blockchain.tip(), block.hash());
     }
+
+    #[test]
+    fn equal_work_tie_break() {
+        let target = hex!("0fffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff");
+        let genesis_hash = Blockchain::new().tip();
+        let a = block_with_target(&genesis_hash, target);
+        let b = block_with_target(&genesis_hash, target);
+        let smaller = std::cmp::min(a.hash(), b.hash());
+
+        // the tip does not depend on the arrival order
+        let mut blockchain = Blockchain::new();
+        blockchain.insert(&a).unwrap();
+        blockchain.insert(&b).unwrap();
+        assert_eq!(blockchain.tip(), smaller);
+        let mut blockchain = Blockchain::new();
+        blockchain.insert(&b).unwrap();
+        blockchain.insert(&a).unwrap();
+        assert_eq!(blockchain.tip(), smaller);
+        assert_eq!(blockchain.total_work(&a.hash()), blockchain.total_work(&b.hash()));
+    }
+
+    fn params_with_rule(retarget: RetargetRule) -> ChainParams {
+        ChainParams {
+            initial_target: hex!("0000ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff").into(),
+            max_target: hex!("0001ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff").into(),
+            retarget,
+            ..Default::default()
+        }
+    }
+
+    /// Extend the tip with a block following the retarget rule, `spacing` ms after its parent
+    fn extend(blockchain: &mut Blockchain, spacing: u128) -> Block {
+        let parent = blockchain.tip();
+        let mut block = generate_random_block(&parent);
+        block.header.timestamp = blockchain.get_block(&parent).unwrap().header.timestamp + spacing;
+        block.header.difficulty = blockchain.next_difficulty(&parent).unwrap();
+        blockchain.insert(&block).unwrap();
+        block
+    }
+
+    #[test]
+    fn retarget_every_interval() {
+        let params = params_with_rule(RetargetRule::Interval { interval: 4, block_time: 1000 });
+        let initial_target = params.initial_target;
+        let mut blockchain = Blockchain::open(params, Box::new(MemoryStore)).unwrap();
+        // blocks twice as fast as expected, the target is unchanged within the interval
+        for _ in 0..3 {
+            assert_eq!(extend(&mut blockchain, 500).get_difficulty(), initial_target);
+        }
+        let halved = initial_target.scale(1, 2);
+        for _ in 0..4 {
+            assert_eq!(extend(&mut blockchain, 500).get_difficulty(), halved);
+        }
+        // ten times slower from now on, the next retarget still sees the fast blocks
+        let quarter = halved.scale(1, 2);
+        for _ in 0..4 {
+            assert_eq!(extend(&mut blockchain, 10000).get_difficulty(), quarter);
+        }
+        // the adjustment is clamped to 4x
+        assert_eq!(extend(&mut blockchain, 10000).get_difficulty(), quarter.scale(4, 1));
+        // and never exceeds the maximum target
+        for _ in 0..3 {
+            extend(&mut blockchain, 10000);
+        }
+        assert_eq!(extend(&mut blockchain, 10000).get_difficulty(), blockchain.params().max_target);
+    }
+
+    #[test]
+    fn retarget_moving_average() {
+        let params = params_with_rule(RetargetRule::MovingAverage { window: 3, block_time: 1000 });
+        let initial_target = params.initial_target;
+        let mut blockchain = Blockchain::open(params, Box::new(MemoryStore)).unwrap();
+        // the first child of genesis has no window yet
+        assert_eq!(extend(&mut blockchain, 1000).get_difficulty(), initial_target);
+        // on schedule, the target stays put (up to rounding)
+        for _ in 0..5 {
+            let target = extend(&mut blockchain, 1000).get_difficulty();
+            assert!(target <= initial_target && target > initial_target.scale(999, 1000));
+        }
+        // every slow block makes the next one a little easier
+        let mut previous = blockchain.next_difficulty(&blockchain.tip()).unwrap();
+        for _ in 0..3 {
+            extend(&mut blockchain, 2000);
+            let next = blockchain.next_difficulty(&blockchain.tip()).unwrap();
+            assert!(next > previous);
+            previous = next;
+        }
+    }
+
+    #[test]
+    fn reload_from_file_store() {
+        use super::store::FileStore;
+        let dir = TempDir::new("blockchain-test");
+        let mut blocks = Vec::new();
+        {
+            let mut blockchain = Blockchain::open(ChainParams::default(), Box::new(FileStore::open(dir.path()).unwrap())).unwrap();
+            let mut parent = blockchain.tip();
+            for _ in 0..5 {
+                let block = generate_random_block(&parent);
+                blockchain.insert(&block).unwrap();
+                parent = block.hash();
+                blocks.push(block);
+            }
+            // a fork that does not become the tip
+            blockchain.insert(&generate_random_block(&blocks[1].hash())).unwrap();
+        }
+        // simulate a crash in the middle of appending a record
+        {
+            use std::io::Write;
+            let mut file = std::fs::OpenOptions::new().append(true).open(dir.path().join("blocks.dat")).unwrap();
+            file.write_all(&[42, 0, 0, 0, 1, 2, 3]).unwrap();
+        }
+        let blockchain = Blockchain::open(ChainParams::default(), Box::new(FileStore::open(dir.path()).unwrap())).unwrap();
+        assert_eq!(blockchain.tip(), blocks[4].hash());
+        assert_eq!(blockchain.all_blocks().len(), 6);
+        let longest_chain = blockchain.all_blocks_in_longest_chain();
+        assert_eq!(longest_chain.len(), 6);
+        for (i, block) in blocks.iter().enumerate() {
+            assert_eq!(longest_chain[i + 1], block.hash());
+        }
+    }
+
+    #[test]
+    fn insert_fails_when_store_fails() {
+        struct BrokenStore;
+        impl BlockStore for BrokenStore {
+            fn put(&mut self, _block: &Block) -> io::Result<()> {
+                Err(io::Error::other("disk full"))
+            }
+            fn load(&mut self) -> io::Result<Vec<Block>> {
+                Ok(Vec::new())
+            }
+        }
+        let mut blockchain = Blockchain::open(ChainParams::default(), Box::new(BrokenStore)).unwrap();
+        let genesis_hash = blockchain.tip();
+        let block = generate_random_block(&genesis_hash);
+        assert!(blockchain.insert(&block).is_err());
+        assert!(!blockchain.exist(&block.hash()));
+        assert_eq!(blockchain.tip(), genesis_hash);
+    }
 }
 
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
\ No newline at end of file
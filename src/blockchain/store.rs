@@ -0,0 +1,131 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use log::{info, warn};
+use ring::digest;
+use crate::types::block::Block;
+
+const BLOCK_FILE: &str = "blocks.dat";
+const RECORD_HEADER_LEN: usize = 4 + 32; // payload length + sha256 checksum of the payload
+
+/// Storage backend used by `Blockchain` to persist blocks.
+/// The blockchain keeps its own in-memory index, so a store only has to append blocks
+/// durably and hand them back (parents before children) when the node restarts.
+pub trait BlockStore: Send {
+    /// Persist a block. Only returns once the block is safely written.
+    fn put(&mut self, block: &Block) -> io::Result<()>;
+
+    /// Read back every stored block, in the order they were inserted.
+    fn load(&mut self) -> io::Result<Vec<Block>>;
+}
+
+/// Replace the content of `path` with `bytes`, so that a crash leaves either the old or the new
+/// content: the bytes go to a temporary file that is synced before being renamed over `path`, and
+/// the directory is synced after the rename. On unix the file is created with permissions `mode`.
+pub fn replace_file(path: &Path, bytes: &[u8], mode: u32) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    // a leftover of an interrupted write may have other permissions
+    if tmp.exists() {
+        fs::remove_file(&tmp)?;
+    }
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(mode);
+    }
+    #[cfg(not(unix))]
+    let _ = mode;
+    let mut file = options.open(&tmp)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)?;
+    #[cfg(unix)]
+    {
+        let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or_else(|| Path::new("."));
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Keeps nothing; the blockchain is lost when the node stops.
+#[derive(Default)]
+pub struct MemoryStore;
+
+impl BlockStore for MemoryStore {
+    fn put(&mut self, _block: &Block) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn load(&mut self) -> io::Result<Vec<Block>> {
+        Ok(Vec::new())
+    }
+}
+
+/// Append-only block file inside a data directory.
+/// Every record is `[len: u32 LE][sha256(payload)][payload]` where the payload is the bincode
+/// encoded block. A torn write at the end of the file (e.g. after a crash) fails the length or
+/// checksum check and is truncated away on the next `load`.
+pub struct FileStore {
+    path: PathBuf,
+    file: File,
+}
+
+impl FileStore {
+    pub fn open(dir: &Path) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let path = dir.join(BLOCK_FILE);
+        let file = OpenOptions::new().read(true).append(true).create(true).open(&path)?;
+        Ok(Self { path, file })
+    }
+}
+
+impl BlockStore for FileStore {
+    fn put(&mut self, block: &Block) -> io::Result<()> {
+        let payload = bincode::serialize(block).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let checksum = digest::digest(&digest::SHA256, &payload);
+        let mut record = Vec::with_capacity(RECORD_HEADER_LEN + payload.len());
+        record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        record.extend_from_slice(checksum.as_ref());
+        record.extend_from_slice(&payload);
+        // a single write followed by fsync, so a record is either complete or detectably torn
+        self.file.write_all(&record)?;
+        self.file.sync_data()
+    }
+
+    fn load(&mut self) -> io::Result<Vec<Block>> {
+        let mut bytes = Vec::new();
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.read_to_end(&mut bytes)?;
+
+        let mut blocks = Vec::new();
+        let mut offset = 0;
+        while offset + RECORD_HEADER_LEN <= bytes.len() {
+            let mut len = [0u8; 4];
+            len.copy_from_slice(&bytes[offset..offset + 4]);
+            let len = u32::from_le_bytes(len) as usize;
+            let start = offset + RECORD_HEADER_LEN;
+            if start + len > bytes.len() {
+                break;
+            }
+            let payload = &bytes[start..start + len];
+            if digest::digest(&digest::SHA256, payload).as_ref() != &bytes[offset + 4..start] {
+                break;
+            }
+            match bincode::deserialize::<Block>(payload) {
+                Ok(block) => blocks.push(block),
+                Err(_) => break,
+            }
+            offset = start + len;
+        }
+
+        if offset < bytes.len() {
+            warn!("Truncating {} corrupted bytes at the end of {}", bytes.len() - offset, self.path.display());
+            self.file.set_len(offset as u64)?;
+            self.file.sync_all()?;
+        }
+        info!("Loaded {} blocks from {}", blocks.len(), self.path.display());
+        Ok(blocks)
+    }
+}
@@ -1,4 +1,4 @@
-use log::{info, debug};
+use log::{info, debug, warn};
 use std::time;
 use std::thread;
 use std::sync::{Arc, Mutex};
@@ -13,14 +13,21 @@ use crate::blockchain::Blockchain;
 use crate::types::mempool::Mempool;
 use crate::types::state::{State, StatePerBlock};
 use crate::network::server::Handle as ServerHandle;
+use crate::miner::Handle as MinerHandle;
 use crate::types::address::Address;
 use crate::types::transaction::{SignedTransaction, sign, Transaction};
 use crate::types::key_pair;
 use crate::network::message::Message;
 
+/// Largest fee offered by generated transactions
+const MAX_FEE: u32 = 100;
+/// Seconds to wait before looking again when none of the keys has coins to send
+const NO_FUNDS_BACKOFF: u64 = 5;
+
 #[derive(Clone)]
 pub struct TransactionGenerator {
     server: ServerHandle,
+    miner: MinerHandle,
     mempool: Arc<Mutex<Mempool>>,
     state_per_block: Arc<Mutex<StatePerBlock>>,
     blockchain: Arc<Mutex<Blockchain>>,
@@ -30,6 +37,7 @@ pub struct TransactionGenerator {
 impl TransactionGenerator {
     pub fn new(
         server: &ServerHandle,
+        miner: &MinerHandle,
         mempool: &Arc<Mutex<Mempool>>,
         state_per_block: &Arc<Mutex<StatePerBlock>>,
         blockchain: &Arc<Mutex<Blockchain>>,
@@ -37,6 +45,7 @@ impl TransactionGenerator {
     ) -> Self {
         Self { 
             server: server.clone(), 
+            miner: miner.clone(),
             mempool: Arc::clone(mempool),
             state_per_block: Arc::clone(state_per_block),
             blockchain: Arc::clone(blockchain),
@@ -56,6 +65,7 @@ impl TransactionGenerator {
 
     fn generate_transactions(&mut self, theta: u64) {
         let mut rng = thread_rng();
+        let mut waiting_for_funds = false;
     
         loop {
             // get the tip of the blockchain
@@ -72,24 +82,30 @@ impl TransactionGenerator {
                 cur_state = state_per_block.get_state(&tip_hash);
             }
     
-            // Randomly select a sender with non-zero balance
-            let mut sender_index;
-            let mut sender_pub_key;
-            let mut sender_account;
-            loop {
-                sender_index = rng.gen_range(0..self.vec_key_pairs.len());
-                sender_pub_key = self.vec_key_pairs[sender_index].public_key().clone();  // Clone here to avoid later immutable borrow
-                sender_account = Address::from_public_key_bytes(sender_pub_key.as_ref());
-                if !cur_state.exist(&sender_account) {
-                    continue;
-                }
-                if cur_state.get_balance(&sender_account) > 0 {
-                    break;
+            // Randomly select a sender that can send at least one coin (values start at 1)
+            let funded: Vec<usize> = (0..self.vec_key_pairs.len())
+                .filter(|&i| {
+                    let account = Address::from_public_key_bytes(self.vec_key_pairs[i].public_key().as_ref());
+                    cur_state.exist(&account) && cur_state.get_balance(&account) > 1
+                })
+                .collect();
+            if funded.is_empty() {
+                // e.g. a fresh key outside the premine, until it mines some coins
+                if !waiting_for_funds {
+                    warn!("No funded key to send transactions from, waiting for coins");
+                    waiting_for_funds = true;
                 }
+                thread::sleep(time::Duration::from_secs(NO_FUNDS_BACKOFF));
+                continue;
             }
+            waiting_for_funds = false;
+            let sender_index = funded[rng.gen_range(0..funded.len())];
+            let sender_pub_key = self.vec_key_pairs[sender_index].public_key().clone();  // Clone here to avoid later immutable borrow
+            let sender_account = Address::from_public_key_bytes(sender_pub_key.as_ref());
     
             // Generate a valid transaction value and nonce
             let value: u32 = rng.gen_range(1..cur_state.get_balance(&sender_account));
+            let fee: u32 = rng.gen_range(0..=std::cmp::min(MAX_FEE, cur_state.get_balance(&sender_account) - value));
             let n = cur_state.get_nonce(&sender_account) + 1;
     
             // Generate a new receiver account with 10% probability
@@ -116,6 +132,7 @@ impl TransactionGenerator {
             let tx = Transaction {
                 receiver: receiver_account,
                 value,
+                fee,
                 account_nonce: n,
             };
             let signature = sign(&tx, &self.vec_key_pairs[sender_index]);
@@ -128,8 +145,10 @@ impl TransactionGenerator {
             // Insert into the mempool and broadcast
             {
                 let mut mempool = self.mempool.lock().unwrap();
-                mempool.insert(&signed_tx);
-                self.server.broadcast(Message::NewTransactionHashes(vec![signed_tx.hash()]));
+                if mempool.insert(&signed_tx).is_ok() {
+                    self.miner.update();
+                    self.server.broadcast(Message::NewTransactionHashes(vec![signed_tx.hash()]));
+                }
             }
     
             // Control generation frequency
@@ -8,23 +8,43 @@ pub mod types;
 pub mod miner;
 pub mod network;
 pub mod generator;
+pub mod validation;
+#[cfg(test)]
+mod test_util;
 
 use blockchain::Blockchain;
-use types::mempool::Mempool;
+use blockchain::store::{FileStore, MemoryStore};
+use types::mempool::{Mempool, MempoolConfig};
+use miner::{EmptyBlockPolicy, MinerConfig};
+use miner::strategy::StrategyConfig;
 use generator::generator::TransactionGenerator;
 use types::state::{StatePerBlock};
+use types::params::{ChainParams, RetargetRule};
 use crate::types::key_pair;
-use ring::signature::{KeyPair, Ed25519KeyPair, Signature};
+use crate::types::address::Address;
+use crate::types::hash::Hashable;
+use crate::validation::validate_block;
+use ring::signature::KeyPair;
 use clap::clap_app;
 use smol::channel;
-use log::{error, info};
-use api::Server as ApiServer;
+use log::{error, info, warn};
+use api::{Context as ApiContext, Server as ApiServer};
 use std::net;
+use std::path::Path;
 use std::process;
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time;
 
+/// Name of the node key inside the data directory
+const KEY_FILE: &str = "node.key";
+/// Name of the mempool dump inside the data directory
+const MEMPOOL_FILE: &str = "mempool.dat";
+/// Seconds between periodic mempool dumps
+const MEMPOOL_SAVE_INTERVAL: u64 = 60;
+/// Seconds between sweeps of the mempool for expired transactions
+const MEMPOOL_EXPIRE_INTERVAL: u64 = 10;
+
 fn main() {
     // parse command line arguments
     let matches = clap_app!(Bitcoin =>
@@ -35,6 +55,19 @@ fn main() {
      (@arg api_addr: --api [ADDR] default_value("127.0.0.1:7000") "Sets the IP address and the port of the API server")
      (@arg known_peer: -c --connect ... [PEER] "Sets the peers to connect to at start")
      (@arg p2p_workers: --("p2p-workers") [INT] default_value("4") "Sets the number of worker threads for P2P server")
+     (@arg genesis: --genesis [FILE] "Sets the JSON file with the genesis block and consensus parameters; uses the default testnet if omitted")
+     (@arg retarget: --retarget [RULE] "Overrides the difficulty retarget rule: fixed, interval:<blocks>:<block time ms> or average:<blocks>:<block time ms>")
+     (@arg max_future_drift: --("max-future-drift") [MS] "Overrides how far ahead of the local clock a block timestamp may be, in milliseconds")
+     (@arg data_dir: --("data-dir") [DIR] "Sets the directory where blocks are persisted; keeps everything in memory if omitted")
+     (@arg mempool_max_txs: --("mempool-max-txs") [N] "Sets the largest number of transactions in the mempool")
+     (@arg mempool_max_bytes: --("mempool-max-bytes") [BYTES] "Sets the largest total size of the transactions in the mempool")
+     (@arg mempool_ttl: --("mempool-ttl") [SECS] "Sets how long a transaction may wait in the mempool")
+     (@arg mempool_fee_bump: --("mempool-fee-bump") [PERCENT] "Sets how much higher a fee must be to replace a pending transaction with the same nonce")
+     (@arg key_file: --("key-file") [FILE] conflicts_with("key_seed") "Sets the file holding the node key, generating it if it does not exist")
+     (@arg key_seed: --("key-seed") [SEED] "Derives the node key from a seed: 64 hex digits, or a byte N repeated 32 times")
+     (@arg miner_threads: --("miner-threads") [N] default_value("1") "Sets the number of mining threads")
+     (@arg mining_strategy: --("mining-strategy") [STRATEGY] "Sets when mined blocks are published: honest, selfish, selfish:<max lead>, stubborn or stubborn:<lead,fork>; defaults to honest")
+     (@arg empty_blocks: --("empty-blocks") [POLICY] "Sets when blocks with few transactions are mined: always, min-txs:<count> or wait:<ms>; defaults to min-txs:1")
     )
     .get_matches();
 
@@ -42,11 +75,120 @@ fn main() {
     let verbosity = matches.occurrences_of("verbose") as usize;
     stderrlog::new().verbosity(verbosity).init().unwrap();
 
-    let blockchain = Blockchain::new();
-    let genisis_hash = blockchain.tip();
+    // parse consensus parameters
+    let mut params = match matches.value_of("genesis") {
+        Some(file) => ChainParams::load(Path::new(file)).unwrap_or_else(|e| {
+            error!("Error loading genesis file {}: {}", file, e);
+            process::exit(1);
+        }),
+        None => ChainParams::default(),
+    };
+
+    if let Some(rule) = matches.value_of("retarget") {
+        params.retarget = rule.parse::<RetargetRule>().unwrap_or_else(|e| {
+            error!("Error parsing retarget rule: {}", e);
+            process::exit(1);
+        });
+    }
+
+    if let Some(drift) = matches.value_of("max_future_drift") {
+        params.max_future_drift = drift.parse::<u64>().unwrap_or_else(|e| {
+            error!("Error parsing max future drift: {}", e);
+            process::exit(1);
+        });
+    }
+
+    let blockchain = match matches.value_of("data_dir") {
+        Some(dir) => FileStore::open(Path::new(dir))
+            .and_then(|store| Blockchain::open(params.clone(), Box::new(store)))
+            .unwrap_or_else(|e| {
+                error!("Error opening data directory {}: {}", dir, e);
+                process::exit(1);
+            }),
+        None => Blockchain::open(params.clone(), Box::new(MemoryStore)).unwrap(),
+    };
+    let genisis_hash = blockchain.block_at_height(0).unwrap().hash();
+    let mut state_per_block = StatePerBlock::new(&genisis_hash, &params);
+    // re-execute the reloaded blocks to rebuild their states, checking them first in case the
+    // data directory was written under other parameters
+    for block in blockchain.all_blocks() {
+        if let Err(e) = validate_block(&block, &blockchain, &state_per_block) {
+            error!("Stored block {} is invalid: {}", block.hash(), e);
+            process::exit(1);
+        }
+        state_per_block.update_with_block(&block);
+    }
+    // parse mempool limits
+    let mut mempool_config = MempoolConfig::default();
+    let parse_limit = |name: &str| {
+        matches.value_of(name).map(|value| {
+            value.parse::<usize>().unwrap_or_else(|e| {
+                error!("Error parsing {}: {}", name, e);
+                process::exit(1);
+            })
+        })
+    };
+    if let Some(max) = parse_limit("mempool_max_txs") {
+        mempool_config.max_transactions = max;
+    }
+    if let Some(max) = parse_limit("mempool_max_bytes") {
+        mempool_config.max_bytes = max;
+    }
+    if let Some(ttl) = parse_limit("mempool_ttl") {
+        mempool_config.ttl = ttl as u128 * 1000;
+    }
+    if let Some(bump) = parse_limit("mempool_fee_bump") {
+        mempool_config.min_fee_bump = bump as u32;
+    }
+    let mut mempool = Mempool::with_config(mempool_config);
+    let tip_state = state_per_block.get_state(&blockchain.tip());
+    mempool.revalidate(&tip_state);
+    // reload the transactions pending at the last shutdown
+    let mempool_file = matches.value_of("data_dir").map(|dir| Path::new(dir).join(MEMPOOL_FILE));
+    if let Some(path) = &mempool_file {
+        if path.exists() {
+            match mempool.restore(path, &tip_state) {
+                Ok(count) => info!("Restored {} pending transactions from {}", count, path.display()),
+                Err(e) => error!("Error restoring mempool from {}: {}", path.display(), e),
+            }
+        }
+    }
     let blockchain = Arc::new(Mutex::new(blockchain));
-    let mempool = Arc::new(Mutex::new(Mempool::new()));
-    let state_per_block = Arc::new(Mutex::new(StatePerBlock::new(&genisis_hash)));
+    let mempool = Arc::new(Mutex::new(mempool));
+    // the tip may not move for a while, expire old transactions on a timer too
+    {
+        let mempool = Arc::clone(&mempool);
+        thread::spawn(move || loop {
+            thread::sleep(time::Duration::from_secs(MEMPOOL_EXPIRE_INTERVAL));
+            mempool.lock().unwrap().expire();
+        });
+    }
+    // dump the mempool periodically and on ctrl-c
+    if let Some(path) = mempool_file {
+        let save = {
+            let mempool = Arc::clone(&mempool);
+            move || {
+                if let Err(e) = mempool.lock().unwrap().save(&path) {
+                    warn!("Error saving mempool to {}: {}", path.display(), e);
+                }
+            }
+        };
+        let periodic_save = save.clone();
+        thread::spawn(move || loop {
+            thread::sleep(time::Duration::from_secs(MEMPOOL_SAVE_INTERVAL));
+            periodic_save();
+        });
+        ctrlc::set_handler(move || {
+            save();
+            info!("Mempool saved, shutting down");
+            process::exit(0);
+        })
+        .unwrap_or_else(|e| {
+            error!("Error setting shutdown handler: {}", e);
+            process::exit(1);
+        });
+    }
+    let state_per_block = Arc::new(Mutex::new(state_per_block));
 
     // parse p2p server address
     let p2p_addr = matches
@@ -58,23 +200,29 @@ fn main() {
             process::exit(1);
         });
 
-    let node1_addr: net::SocketAddr = "127.0.0.1:6000".parse().expect("Invalid address");
-    let node2_addr: net::SocketAddr = "127.0.0.1:6001".parse().expect("Invalid address");
-    let node3_addr: net::SocketAddr = "127.0.0.1:6002".parse().expect("Invalid address");
-
-    let mut key_pair = key_pair::random();
-    if p2p_addr == node1_addr {
-        key_pair = Ed25519KeyPair::from_seed_unchecked(&[0; 32]).unwrap();
-    } else if p2p_addr == node2_addr {
-        key_pair = Ed25519KeyPair::from_seed_unchecked(&[1; 32]).unwrap();
-    } else if p2p_addr == node3_addr {
-        key_pair = Ed25519KeyPair::from_seed_unchecked(&[2; 32]).unwrap();
-    }
-    else {
-        unimplemented!();
-    }
-
-    
+    // load the node identity; without a key file or seed, a fresh key is kept in the data directory,
+    // and without that either there is nowhere to keep the identity across restarts
+    let key_file = matches
+        .value_of("key_file")
+        .map(|file| Path::new(file).to_path_buf())
+        .or_else(|| matches.value_of("data_dir").map(|dir| Path::new(dir).join(KEY_FILE)));
+    let key_pair = if let Some(seed) = matches.value_of("key_seed") {
+        let seed = key_pair::parse_seed(seed).unwrap_or_else(|e| {
+            error!("Error parsing key seed: {}", e);
+            process::exit(1);
+        });
+        key_pair::from_seed(&seed)
+    } else if let Some(path) = key_file {
+        key_pair::load_or_generate(&path).unwrap_or_else(|e| {
+            error!("Error loading key file {}: {}", path.display(), e);
+            process::exit(1);
+        })
+    } else {
+        error!("No node key: pass --key-file, --key-seed or --data-dir");
+        process::exit(1);
+    };
+    let address = Address::from_public_key_bytes(key_pair.public_key().as_ref());
+    info!("Node address {}", address);
 
     // parse api server address
     let api_addr = matches
@@ -93,7 +241,7 @@ fn main() {
     let (server_ctx, server) = network::server::new(p2p_addr, msg_tx).unwrap();
     server_ctx.start().unwrap();
 
-    // start the worker
+    // parse the number of p2p workers
     let p2p_workers = matches
         .value_of("p2p_workers")
         .unwrap()
@@ -102,23 +250,56 @@ fn main() {
             error!("Error parsing P2P workers: {}", e);
             process::exit(1);
         });
+
+    // start the miner
+    let miner_threads = matches
+        .value_of("miner_threads")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing miner threads: {}", e);
+            process::exit(1);
+        });
+    let mut miner_config = MinerConfig { threads: miner_threads, ..MinerConfig::default() };
+    if let Some(policy) = matches.value_of("empty_blocks") {
+        miner_config.empty_blocks = policy.parse::<EmptyBlockPolicy>().unwrap_or_else(|e| {
+            error!("Error parsing empty block policy: {}", e);
+            process::exit(1);
+        });
+    }
+    let (miner_ctx, miner, finished_block_chan) = miner::new(&blockchain, &mempool, &state_per_block, address, miner_config);
+    let strategy = match matches.value_of("mining_strategy") {
+        Some(strategy) => strategy.parse::<StrategyConfig>().unwrap_or_else(|e| {
+            error!("Error parsing mining strategy: {}", e);
+            process::exit(1);
+        }),
+        None => StrategyConfig::default(),
+    };
+    info!("Mining strategy {}", strategy);
+    // the strategy starts out from the chain the network already knows
+    let tip_work = {
+        let blockchain = blockchain.lock().unwrap();
+        blockchain.total_work(&blockchain.tip()).unwrap()
+    };
+    let miner_worker_ctx = miner::worker::Worker::new(&server, &miner, finished_block_chan, &blockchain, &mempool, &state_per_block, strategy.build(tip_work));
+    miner_ctx.start();
+    miner_worker_ctx.start();
+
+    // start the worker
     let worker_ctx = network::worker::Worker::new(
         p2p_workers,
         msg_rx,
         &server,
+        &miner,
         &blockchain,
         &mempool,
         &state_per_block,
     );
     worker_ctx.start();
 
-    // start the miner
-    let (miner_ctx, miner, finished_block_chan) = miner::new(&blockchain, &mempool, &state_per_block);
-    let miner_worker_ctx = miner::worker::Worker::new(&server, finished_block_chan, &blockchain, &state_per_block);
-    miner_ctx.start();
-    miner_worker_ctx.start();
+    let external_mining = miner::external::ExternalMining::new(&blockchain, &mempool, &state_per_block, &miner, address);
 
-    let tx_generator = TransactionGenerator::new(&server, &mempool, &state_per_block, &blockchain, Arc::new(key_pair));
+    let tx_generator = TransactionGenerator::new(&server, &miner, &mempool, &state_per_block, &blockchain, Arc::new(key_pair));
 
     // connect to known peers
     if let Some(known_peers) = matches.values_of("known_peer") {
@@ -157,11 +338,15 @@ fn main() {
     // start the API server
     ApiServer::start(
         api_addr,
-        &miner,
-        &server,
-        &blockchain,
-        &tx_generator,
-        &state_per_block,
+        ApiContext {
+            miner,
+            network: server,
+            blockchain,
+            tx_generator,
+            state_per_block,
+            mempool,
+            external_mining,
+        },
     );
 
     loop {
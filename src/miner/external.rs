@@ -0,0 +1,140 @@
+use log::info;
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use crate::blockchain::Blockchain;
+use crate::types::address::Address;
+use crate::types::block::{Block, Header};
+use crate::types::hash::{H256, Hashable};
+use crate::types::mempool::Mempool;
+use crate::types::state::StatePerBlock;
+use crate::validation::{validate_block, BlockError};
+use super::Handle as MinerHandle;
+
+/// Templates kept around for submissions, the oldest is forgotten first
+const MAX_TEMPLATES: usize = 64;
+
+#[derive(Debug)]
+pub enum SubmitError {
+    /// The merkle root is not one of a recently served template
+    UnknownTemplate(H256),
+    Invalid(BlockError),
+}
+
+impl fmt::Display for SubmitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SubmitError::UnknownTemplate(merkle_root) => write!(f, "no template with merkle root {}", merkle_root),
+            SubmitError::Invalid(e) => write!(f, "invalid block: {}", e),
+        }
+    }
+}
+
+/// Block templates for miners running outside of the node. A template is solved by finding a
+/// nonce for its header (the timestamp may be moved forward too); the transactions stay with
+/// the node and are matched back to a submitted header by its merkle root.
+#[derive(Clone)]
+pub struct ExternalMining {
+    blockchain: Arc<Mutex<Blockchain>>,
+    mempool: Arc<Mutex<Mempool>>,
+    state_per_block: Arc<Mutex<StatePerBlock>>,
+    miner: MinerHandle,
+    /// Receiver of the block rewards
+    address: Address,
+    templates: Arc<Mutex<VecDeque<Block>>>,
+}
+
+impl ExternalMining {
+    pub fn new(
+        blockchain: &Arc<Mutex<Blockchain>>,
+        mempool: &Arc<Mutex<Mempool>>,
+        state_per_block: &Arc<Mutex<StatePerBlock>>,
+        miner: &MinerHandle,
+        address: Address,
+    ) -> Self {
+        Self {
+            blockchain: Arc::clone(blockchain),
+            mempool: Arc::clone(mempool),
+            state_per_block: Arc::clone(state_per_block),
+            miner: miner.clone(),
+            address,
+            templates: Arc::new(Mutex::new(VecDeque::new())),
+        }
+    }
+
+    /// A fresh template on top of the current tip.
+    pub fn template(&self) -> Block {
+        let template = super::build_template(&self.blockchain, &self.mempool, &self.state_per_block, self.address);
+        let mut templates = self.templates.lock().unwrap();
+        if templates.len() == MAX_TEMPLATES {
+            templates.pop_front();
+        }
+        templates.push_back(template.clone());
+        template
+    }
+
+    /// Check a solved header against the template it came from, and pass the block on to the
+    /// miner worker to be inserted and broadcast.
+    pub fn submit(&self, header: Header) -> Result<H256, SubmitError> {
+        let content = self
+            .templates
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|template| template.header.merkle_root == header.merkle_root)
+            .map(|template| template.content.clone())
+            .ok_or(SubmitError::UnknownTemplate(header.merkle_root))?;
+        let block = Block { header, content };
+        {
+            let blockchain = self.blockchain.lock().unwrap();
+            let state_per_block = self.state_per_block.lock().unwrap();
+            validate_block(&block, &blockchain, &state_per_block).map_err(SubmitError::Invalid)?;
+        }
+        let hash = block.hash();
+        info!("External miner solved block {}", hash);
+        self.miner.submit(block);
+        Ok(hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ExternalMining, SubmitError};
+    use crate::blockchain::Blockchain;
+    use crate::types::address::Address;
+    use crate::types::hash::{H256, Hashable};
+    use crate::types::mempool::Mempool;
+    use crate::types::state::StatePerBlock;
+    use crate::validation::BlockError;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn submit_solved_template() {
+        let blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let state_per_block = Arc::new(Mutex::new(StatePerBlock::new(&genesis_hash, blockchain.params())));
+        let blockchain = Arc::new(Mutex::new(blockchain));
+        let mempool = Arc::new(Mutex::new(Mempool::new()));
+        let (_miner_ctx, miner, finished_block_chan) = crate::miner::new(&blockchain, &mempool, &state_per_block, Address::random(), Default::default());
+        let external = ExternalMining::new(&blockchain, &mempool, &state_per_block, &miner, Address::random());
+
+        let mut header = external.template().header;
+        while header.hash() > header.difficulty {
+            header.nonce = header.nonce.wrapping_add(1);
+        }
+        let mut unsolved = header.clone();
+        unsolved.nonce = unsolved.nonce.wrapping_add(1);
+        while unsolved.hash() <= unsolved.difficulty {
+            unsolved.nonce = unsolved.nonce.wrapping_add(1);
+        }
+        let mut unknown = header.clone();
+        unknown.merkle_root = H256::default();
+
+        assert!(matches!(external.submit(unsolved), Err(SubmitError::Invalid(BlockError::InsufficientWork))));
+        assert!(matches!(external.submit(unknown), Err(SubmitError::UnknownTemplate(_))));
+        let hash = external.submit(header).unwrap();
+        // handed to the miner worker
+        assert_eq!(finished_block_chan.try_recv().unwrap().hash(), hash);
+    }
+}
@@ -1,38 +1,134 @@
 pub mod worker;
+pub mod external;
+pub mod strategy;
 
 use log::{info, debug};
 
-use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
+use crossbeam::channel::{unbounded, Receiver, RecvTimeoutError, Sender};
 use std::time;
 
 use std::thread;
 
 use crate::types::block::{Header, Block, Content};
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
 use crate::blockchain::Blockchain;
-use crate::types::state::{State, StatePerBlock, AccountState};
+use crate::types::state::{State, StatePerBlock};
 use crate::types::hash::{H256, Hashable};
 use crate::types::mempool::Mempool;
 use crate::types::merkle::MerkleTree;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use crate::types::transaction::{SignedTransaction, Transaction, verify};
 use crate::types::key_pair;
 use crate::types::address::Address;
-use std::collections::HashMap;
+use crate::validation::validate_transaction;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt;
+use std::str::FromStr;
 
+/// Nonces hashed between two checks for a new template when mining at full speed
+const NONCES_PER_ROUND: u32 = 1 << 12;
 
 enum ControlSignal {
     Start(u64), // the number controls the lambda of interval between block generation
     Update, // update the block in mining, it may due to new blockchain tip or new transaction
+    Pause,
     Exit,
 }
 
-enum OperatingState {
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OperatingState {
     Paused,
     Run(u64),
     ShutDown,
 }
 
+/// When a block with few or no transactions besides the coinbase is worth mining
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EmptyBlockPolicy {
+    /// Mine whatever the mempool offers, even a coinbase-only block
+    Always,
+    /// Wait for at least this many transactions
+    MinTransactions(usize),
+    /// Wait for a transaction at most this many milliseconds after the tip changed, then mine anyway
+    Wait(u64),
+}
+
+impl FromStr for EmptyBlockPolicy {
+    type Err = String;
+
+    /// Parses `always`, `min-txs:<count>` or `wait:<ms>`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split(':').collect();
+        match parts.as_slice() {
+            ["always"] => Ok(EmptyBlockPolicy::Always),
+            ["min-txs", count] => count.parse().map(EmptyBlockPolicy::MinTransactions).map_err(|e| e.to_string()),
+            ["wait", ms] => ms.parse().map(EmptyBlockPolicy::Wait).map_err(|e| e.to_string()),
+            _ => Err(format!("unknown empty block policy {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for EmptyBlockPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EmptyBlockPolicy::Always => write!(f, "always"),
+            EmptyBlockPolicy::MinTransactions(count) => write!(f, "min-txs:{}", count),
+            EmptyBlockPolicy::Wait(ms) => write!(f, "wait:{}", ms),
+        }
+    }
+}
+
+/// Miner settings
+#[derive(Debug, Clone)]
+pub struct MinerConfig {
+    /// Number of threads searching nonces
+    pub threads: usize,
+    pub empty_blocks: EmptyBlockPolicy,
+}
+
+impl Default for MinerConfig {
+    fn default() -> Self {
+        MinerConfig {
+            threads: 1,
+            empty_blocks: EmptyBlockPolicy::MinTransactions(1),
+        }
+    }
+}
+
+/// What the miner is doing, see `Handle::status`
+#[derive(Debug, Clone)]
+pub struct MinerStatus {
+    pub state: OperatingState,
+    /// Parent of the block being mined
+    pub template_parent: Option<H256>,
+    pub hashes: u64,
+    pub blocks_found: u64,
+    /// Average hashes per second while running
+    pub hashrate: f64,
+}
+
+/// Work shared with the hashing threads
+struct Job {
+    /// Bumped whenever the template changes, the threads drop their work when it does
+    generation: u64,
+    state: OperatingState,
+    /// Block being mined, none while paused
+    template: Option<Arc<Block>>,
+    /// Time spent running, not counting the current run
+    mining_time: Duration,
+    running_since: Option<Instant>,
+}
+
+/// State shared by the miner threads and the handles
+struct Shared {
+    job: Mutex<Job>,
+    /// Wakes up the hashing threads when the job changes
+    wakeup: Condvar,
+    hashes: AtomicU64,
+    blocks_found: AtomicU64,
+}
+
 pub struct Context {
     /// Channel for receiving control signal
     control_chan: Receiver<ControlSignal>,
@@ -41,34 +137,73 @@ pub struct Context {
     blockchain: Arc<Mutex<Blockchain>>,
     mempool: Arc<Mutex<Mempool>>,
     state_per_block: Arc<Mutex<StatePerBlock>>,
+    /// Receiver of the block rewards
+    address: Address,
+    config: MinerConfig,
+    shared: Arc<Shared>,
+    /// Parent of the last template, and since when it has been waiting for transactions
+    waiting_since: Option<(H256, Instant)>,
+    /// When to stop waiting for transactions and mine the template as is
+    deadline: Option<Instant>,
 }
 
 #[derive(Clone)]
 pub struct Handle {
     /// Channel for sending signal to the miner thread
     control_chan: Sender<ControlSignal>,
+    /// Channel to the miner worker, for blocks solved outside the miner threads
+    finished_block_chan: Sender<Block>,
+    /// Blocks from the network, for the mining strategy of the miner worker
+    received_block_sender: Sender<H256>,
+    received_block_chan: Receiver<H256>,
+    /// Where the coinbase of the mined blocks pays to
+    address: Address,
+    shared: Arc<Shared>,
 }
 
 pub fn new(blockchain: &Arc<Mutex<Blockchain>>, mempool: &Arc<Mutex<Mempool>>, 
-           state_per_block: &Arc<Mutex<StatePerBlock>>) -> 
+           state_per_block: &Arc<Mutex<StatePerBlock>>, address: Address, config: MinerConfig) -> 
 (Context, Handle, Receiver<Block>) {
     let (signal_chan_sender, signal_chan_receiver) = unbounded();
     let (finished_block_sender, finished_block_receiver) = unbounded();
+    let (received_block_sender, received_block_receiver) = unbounded();
     let blockchain_cloned = Arc::clone(blockchain);
     let mempool_cloned = Arc::clone(mempool);
     let state_per_block_cloned = Arc::clone(state_per_block);
+    let shared = Arc::new(Shared {
+        job: Mutex::new(Job {
+            generation: 0,
+            state: OperatingState::Paused,
+            template: None,
+            mining_time: Duration::ZERO,
+            running_since: None,
+        }),
+        wakeup: Condvar::new(),
+        hashes: AtomicU64::new(0),
+        blocks_found: AtomicU64::new(0),
+    });
 
     let ctx = Context {
         control_chan: signal_chan_receiver,
         operating_state: OperatingState::Paused,
-        finished_block_chan: finished_block_sender,
+        finished_block_chan: finished_block_sender.clone(),
         blockchain: blockchain_cloned,
         mempool: mempool_cloned,
         state_per_block: state_per_block_cloned,
+        address,
+        config,
+        shared: Arc::clone(&shared),
+        waiting_since: None,
+        deadline: None,
     };
 
     let handle = Handle {
         control_chan: signal_chan_sender,
+        finished_block_chan: finished_block_sender,
+        received_block_sender,
+        received_block_chan: received_block_receiver,
+        address,
+        shared,
     };
 
     (ctx, handle, finished_block_receiver)
@@ -78,10 +213,35 @@ pub fn new(blockchain: &Arc<Mutex<Blockchain>>, mempool: &Arc<Mutex<Mempool>>,
 fn test_new() -> (Context, Handle, Receiver<Block>) {
     let blockchain = Blockchain::new();
     let blockchain = Arc::new(Mutex::new(blockchain));
+    let genesis_hash = blockchain.lock().unwrap().tip();
     let mempool = Mempool::new();
     let mempool = Arc::new(Mutex::new(mempool));
-    let state_per_block = StatePerBlock::new(H256::default());
-    new(&blockchain, &mempool, &state_per_block)
+    let state_per_block = Arc::new(Mutex::new(StatePerBlock::new(&genesis_hash, blockchain.lock().unwrap().params())));
+    let config = MinerConfig { empty_blocks: EmptyBlockPolicy::Always, ..MinerConfig::default() };
+    let (ctx, handle, finished_block_chan) = new(&blockchain, &mempool, &state_per_block, Address::random(), config);
+
+    // stand in for the miner worker: extend the chain with every block before passing it on
+    let (block_sender, block_receiver) = unbounded();
+    let miner = handle.clone();
+    thread::spawn(move || {
+        for block in finished_block_chan.iter() {
+            {
+                let mut blockchain = blockchain.lock().unwrap();
+                let mut state_per_block = state_per_block.lock().unwrap();
+                assert!(crate::validation::validate_block(&block, &blockchain, &state_per_block).is_ok());
+                let reorg = blockchain.insert(&block).unwrap();
+                state_per_block.update_with_block(&block);
+                if let Some(reorg) = reorg {
+                    mempool.lock().unwrap().follow_reorg(&blockchain, &state_per_block, &reorg);
+                }
+            }
+            miner.update();
+            if block_sender.send(block).is_err() {
+                return;
+            }
+        }
+    });
+    (ctx, handle, block_receiver)
 }
 
 impl Handle {
@@ -98,184 +258,436 @@ impl Handle {
     pub fn update(&self) {
         self.control_chan.send(ControlSignal::Update).unwrap();
     }
+
+    /// Hand a block solved elsewhere to the miner worker, which inserts and broadcasts it.
+    pub fn submit(&self, block: Block) {
+        self.finished_block_chan.send(block).expect("Send finished block error");
+    }
+
+    /// Tell the miner worker about a block from the network, after it has been inserted.
+    pub fn block_received(&self, hash: H256) {
+        self.received_block_sender.send(hash).unwrap();
+    }
+
+    fn received_blocks(&self) -> Receiver<H256> {
+        self.received_block_chan.clone()
+    }
+
+    fn address(&self) -> Address {
+        self.address
+    }
+
+    /// Stop hashing until the next `start`.
+    pub fn pause(&self) {
+        self.control_chan.send(ControlSignal::Pause).unwrap();
+    }
+
+    pub fn status(&self) -> MinerStatus {
+        let job = self.shared.job.lock().unwrap();
+        let mining_time = job.mining_time + job.running_since.map_or(Duration::ZERO, |since| since.elapsed());
+        let hashes = self.shared.hashes.load(Ordering::Relaxed);
+        let hashrate = if mining_time > Duration::ZERO {
+            hashes as f64 / mining_time.as_secs_f64()
+        } else {
+            0.0
+        };
+        MinerStatus {
+            state: job.state,
+            template_parent: job.template.as_ref().map(|template| template.header.parent),
+            hashes,
+            blocks_found: self.shared.blocks_found.load(Ordering::Relaxed),
+            hashrate,
+        }
+    }
 }
 
 impl Context {
     pub fn start(mut self) {
+        let threads = std::cmp::max(self.config.threads, 1);
+        let hashers: Vec<_> = (0..threads)
+            .map(|index| {
+                let hasher = Hasher {
+                    index: index as u32,
+                    threads: threads as u32,
+                    shared: Arc::clone(&self.shared),
+                    finished_block_chan: self.finished_block_chan.clone(),
+                };
+                thread::Builder::new()
+                    .name(format!("miner-{}", index))
+                    .spawn(move || {
+                        hasher.hash_loop();
+                    })
+                    .unwrap()
+            })
+            .collect();
         thread::Builder::new()
             .name("miner".to_string())
             .spawn(move || {
                 self.miner_loop();
+                for hasher in hashers {
+                    hasher.join().unwrap();
+                }
             })
             .unwrap();
-        info!("Miner initialized into paused mode");
+        info!("Miner initialized into paused mode with {} threads", threads);
     }
 
     fn miner_loop(&mut self) {
-        // FIXME: put this into the loop
-        let mut parent_hash = H256::default();
-        let mut parent_difficulty = H256::default();
-
-        // main mining loop
         loop {
-            // check and react to control signals
-            // println!("Mine a block ...\n");
-            match self.operating_state {
-                OperatingState::Paused => {
-                    let signal = self.control_chan.recv().unwrap();
-                    match signal {
-                        ControlSignal::Exit => {
-                            info!("Miner shutting down");
-                            self.operating_state = OperatingState::ShutDown;
-                        }
-                        ControlSignal::Start(i) => {
-                            info!("Miner starting in continuous mode with lambda {}", i);
-                            self.operating_state = OperatingState::Run(i);
-                        }
-                        ControlSignal::Update => {
-                            // in paused state, don't need to update
-                        }
-                    };
-                    continue;
-                }
-                OperatingState::ShutDown => {
-                    return;
-                }
-                _ => match self.control_chan.try_recv() {
-                    Ok(signal) => {
-                        match signal {
-                            ControlSignal::Exit => {
-                                info!("Miner shutting down");
-                                self.operating_state = OperatingState::ShutDown;
-                            }
-                            ControlSignal::Start(i) => {
-                                info!("Miner starting in continuous mode with lambda {}", i);
-                                self.operating_state = OperatingState::Run(i);
-                            }
-                            ControlSignal::Update => {
-                                unimplemented!()
-                            }
-                        };
-                    }
-                    Err(TryRecvError::Empty) => {}
-                    Err(TryRecvError::Disconnected) => panic!("Miner control channel detached"),
+            let signal = match self.deadline {
+                Some(deadline) => match self.control_chan.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                    Ok(signal) => signal,
+                    // done waiting for transactions
+                    Err(RecvTimeoutError::Timeout) => ControlSignal::Update,
+                    Err(RecvTimeoutError::Disconnected) => panic!("Miner control channel detached"),
                 },
+                None => self.control_chan.recv().unwrap(),
+            };
+            self.handle_signal(signal);
+            // a burst of updates only needs one new template
+            while let Ok(signal) = self.control_chan.try_recv() {
+                self.handle_signal(signal);
             }
+            self.publish();
             if let OperatingState::ShutDown = self.operating_state {
                 return;
             }
+        }
+    }
 
-            {
-                let blockchain = self.blockchain.lock().unwrap();
-                parent_hash = blockchain.tip();
-                parent_difficulty = blockchain.get_block(&parent_hash).get_difficulty();
+    fn handle_signal(&mut self, signal: ControlSignal) {
+        match (signal, &self.operating_state) {
+            (_, OperatingState::ShutDown) => {}
+            (ControlSignal::Exit, _) => {
+                info!("Miner shutting down");
+                self.operating_state = OperatingState::ShutDown;
             }
-
-            let mut cur_state;
-            {
-                let state_per_block = self.state_per_block.lock().unwrap();
-                assert!(state_per_block.exist(&parent_hash));
-                cur_state = state_per_block.get_state(&parent_hash); // use cur_state to simulate transactions
+            (ControlSignal::Start(i), _) => {
+                info!("Miner starting in continuous mode with lambda {}", i);
+                self.operating_state = OperatingState::Run(i);
             }
+            (ControlSignal::Pause, _) => {
+                info!("Miner paused");
+                self.operating_state = OperatingState::Paused;
+            }
+            (ControlSignal::Update, _) => {
+                // the template is rebuilt in any case
+            }
+        }
+    }
 
-            // insert the transactions into content
-
-            let tx_limit = 50; // NOTE: this is a temporary value, you can change it
+    /// Hand the hashing threads a fresh template, or stop them when not running.
+    fn publish(&mut self) {
+        self.deadline = None;
+        let template = match self.operating_state {
+            OperatingState::Run(_) => {
+                let template = self.build_template();
+                if self.worth_mining(&template) {
+                    Some(Arc::new(template))
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        };
+        let mut job = self.shared.job.lock().unwrap();
+        let running = matches!(self.operating_state, OperatingState::Run(_));
+        match job.running_since {
+            None if running => job.running_since = Some(Instant::now()),
+            Some(since) if !running => {
+                job.mining_time += since.elapsed();
+                job.running_since = None;
+            }
+            _ => {}
+        }
+        job.generation += 1;
+        job.state = self.operating_state;
+        job.template = template;
+        self.shared.wakeup.notify_all();
+    }
 
-            let mut block_txs = Vec::new();
-            {
-                let mut mempool = self.mempool.lock().unwrap();
-                for tx in mempool.all_transactions() {
-                    // tx check
-                    // 1. verify the signature
-                    if !verify(&tx.transaction, &tx.public_key, &tx.signature) {
-                        mempool.remove(&tx);
-                        println!("Invalid tx signature\n");
-                        continue;
-                    }
-                    
-                    let sender = Address::from_public_key_bytes(&tx.public_key);
-                    let receiver = tx.transaction.receiver.clone();
-                    let value = tx.transaction.value;
-                    let nonce = tx.transaction.account_nonce;
-                    assert!(cur_state.exist(&sender));
-
-                    // 2. check the balance and nonce
-                    if (cur_state.get_balance(&sender) < value) || (cur_state.get_nonce(&sender)+1 != nonce) {
-                        mempool.remove(&tx);
-                        // println!("Invalid tx balance or nonce\n");
-                        continue;
+    /// Whether the template has enough transactions under the empty block policy. When waiting
+    /// for transactions, sets the deadline after which the template is mined anyway.
+    fn worth_mining(&mut self, template: &Block) -> bool {
+        let transactions = template.content.transactions.len() - 1;
+        match self.config.empty_blocks {
+            EmptyBlockPolicy::Always => true,
+            EmptyBlockPolicy::MinTransactions(count) => transactions >= count,
+            EmptyBlockPolicy::Wait(_) if transactions > 0 => true,
+            EmptyBlockPolicy::Wait(ms) => {
+                let parent = template.header.parent;
+                let since = match self.waiting_since {
+                    Some((waiting_parent, since)) if waiting_parent == parent => since,
+                    _ => {
+                        let now = Instant::now();
+                        self.waiting_since = Some((parent, now));
+                        now
                     }
+                };
+                let deadline = since + Duration::from_millis(ms);
+                if Instant::now() >= deadline {
+                    true
+                } else {
+                    self.deadline = Some(deadline);
+                    false
+                }
+            }
+        }
+    }
+}
 
-                    block_txs.push(tx.clone());
-                    cur_state.insert(sender, AccountState{nonce: nonce+1, 
-                                                          balance: cur_state.get_balance(&sender) - value,
-                                                         } );
-
-                    if cur_state.exist(&receiver) {
-                        cur_state.insert(receiver, AccountState{
-                                                                nonce: cur_state.get_nonce(&receiver),
-                                                                balance: cur_state.get_balance(&receiver) + value,} );
-                    } else {
-                        cur_state.insert(receiver, AccountState{nonce: 0, 
-                                                                balance: value});
+/// A thread searching its share of the nonces of the current template
+struct Hasher {
+    index: u32,
+    threads: u32,
+    shared: Arc<Shared>,
+    finished_block_chan: Sender<Block>,
+}
+
+impl Hasher {
+    fn hash_loop(&self) {
+        let mut generation = 0;
+        loop {
+            // wait for a template not worked on yet
+            let (template, lambda) = {
+                let mut job = self.shared.job.lock().unwrap();
+                loop {
+                    match (job.state, &job.template) {
+                        (OperatingState::ShutDown, _) => return,
+                        (OperatingState::Run(lambda), Some(template)) if job.generation != generation => {
+                            generation = job.generation;
+                            break (Arc::clone(template), lambda);
+                        }
+                        _ => {}
                     }
+                    job = self.shared.wakeup.wait(job).unwrap();
+                }
+            };
 
-                    // println!("Valid tx\n");
-                    if block_txs.len() == tx_limit {
-                        break;
+            // each thread searches its own contiguous share of the nonces, one at a time when throttled
+            let mut header = template.header.clone();
+            let range = NonceRange::share(header.nonce, self.index, self.threads);
+            header.nonce = range.first;
+            let round = if lambda == 0 { NONCES_PER_ROUND } else { 1 };
+            loop {
+                // keep the timestamp current, the template may be long lived
+                let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
+                header.timestamp = std::cmp::max(now, header.timestamp);
+                let solved = search_nonce(&mut header, round, range);
+                self.shared.hashes.fetch_add(solved.unwrap_or(round) as u64, Ordering::Relaxed);
+                if solved.is_some() {
+                    let mut job = self.shared.job.lock().unwrap();
+                    // another thread may have solved the same template
+                    if job.generation == generation {
+                        // this template is spent, the threads idle until the miner worker signals the new tip
+                        job.generation += 1;
+                        job.template = None;
+                        self.shared.blocks_found.fetch_add(1, Ordering::Relaxed);
+                        let block = Block { header, content: template.content.clone() };
+                        debug!("Mined block {} with {} transactions", block.hash(), block.content.transactions.len());
+                        self.finished_block_chan.send(block).expect("Send finished block error");
                     }
+                    break;
+                }
+                if lambda != 0 {
+                    thread::sleep(time::Duration::from_micros(lambda));
+                }
+                if self.shared.job.lock().unwrap().generation != generation {
+                    break;
                 }
             }
-            
-            let difficulty = parent_difficulty;
-            let nonce = rand::random::<u32>();
-            let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
-            let content = Content{ transactions: block_txs };
-            let merkle_root = MerkleTree::new(&content.transactions.as_slice()).root();
-            let header = Header {
-                parent: parent_hash,
-                nonce: nonce,
-                difficulty: difficulty,
-                timestamp: timestamp,
-                merkle_root: merkle_root,
-            };
+        }
+    }
+}
 
-            
-            let block = Block {header, content};
-            if block.hash() <= difficulty && !block.get_transactions().is_empty() {
+impl Context {
+    fn build_template(&self) -> Block {
+        build_template(&self.blockchain, &self.mempool, &self.state_per_block, self.address)
+    }
+}
 
-                println!("Block tx size: {}", block.content.transactions.len());
+/// Assemble a block on top of the current tip with the best paying transactions of the mempool,
+/// paying the rewards to `address`. The nonce starts at a random point so that miners sharing an
+/// address do not repeat each other.
+pub(crate) fn build_template(blockchain: &Mutex<Blockchain>, mempool: &Mutex<Mempool>,
+                             state_per_block: &Mutex<StatePerBlock>, address: Address) -> Block {
+    let params;
+    let parent_hash;
+    let height;
+    let difficulty;
+    let median_time_past;
+    {
+        let blockchain = blockchain.lock().unwrap();
+        params = blockchain.params().clone();
+        parent_hash = blockchain.tip();
+        height = blockchain.get_height(&parent_hash).unwrap() + 1;
+        difficulty = blockchain.next_difficulty(&parent_hash).unwrap();
+        median_time_past = blockchain.median_time_past(&parent_hash).unwrap();
+    }
 
-                // TODO remove transactions in this block from mempool 
-                {
-                    let mut mempool = self.mempool.lock().unwrap();
-                    for tx in block.content.transactions.iter() {
-                        mempool.remove(&tx);
-                    }
-                }
+    let mut cur_state;
+    {
+        let state_per_block = state_per_block.lock().unwrap();
+        assert!(state_per_block.exist(&parent_hash));
+        cur_state = state_per_block.get_state(&parent_hash); // use cur_state to simulate transactions
+    }
+
+    // the header and the coinbase have a fixed size, every selected transaction adds its own
+    let reward = params.block_reward_at(height);
+    let block_size = Block {
+        header: Header { parent: parent_hash, nonce: 0, difficulty, timestamp: 0, merkle_root: H256::default() },
+        content: Content::new(vec![SignedTransaction::coinbase(address, reward, height as u32)]),
+    }.size();
+
+    // the coinbase may not overflow the miner's balance, without room for the reward it is left out
+    let balance = if cur_state.exist(&address) { cur_state.get_balance(&address) } else { 0 };
+    let room = (u32::MAX - balance).checked_sub(reward);
+
+    // only transactions following their sender's nonce without a gap can go in
+    let candidates: Vec<SignedTransaction> = mempool.lock().unwrap().executable().cloned().collect();
+    let selected = select_transactions(candidates, &mut cur_state,
+                                       params.max_block_transactions.saturating_sub(1),
+                                       params.max_block_size.saturating_sub(block_size),
+                                       room.unwrap_or(0) as u64);
+
+    // the coinbase takes the first slot and collects the fees, which the selection kept within its range
+    let fees: u64 = selected.iter().map(|tx| tx.transaction.fee as u64).sum();
+    let mut block_txs = Vec::new();
+    if room.is_some() {
+        block_txs.push(SignedTransaction::coinbase(address, reward + fees as u32, height as u32));
+    }
+    block_txs.extend(selected);
+
+    // must be after the median time past even if the local clock is behind
+    let timestamp = std::cmp::max(SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis(),
+                                  median_time_past + 1);
+    let content = Content{ transactions: block_txs };
+    let merkle_root = MerkleTree::new(content.transactions.as_slice()).root();
+    let header = Header {
+        parent: parent_hash,
+        nonce: rand::random::<u32>(),
+        difficulty,
+        timestamp,
+        merkle_root,
+    };
+    Block {header, content}
+}
+
+/// A contiguous run of `len` nonces starting at `first`, wrapping around past `u32::MAX`
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct NonceRange {
+    first: u32,
+    len: u64,
+}
 
-                // {
-                //     let mut blockchain = self.blockchain.lock().unwrap();
-                //     blockchain.insert(&block);
-                // }
-
-                // {
-                //     let mut state_per_block = self.state_per_block.lock().unwrap();
-                //     state_per_block.update_with_block(&block);
-                // }
-                
-                self.finished_block_chan.send(block.clone()).expect("Send finished block error");
+impl NonceRange {
+    /// The `index`th of `parts` ranges that split the whole nonce space, counting from `start`
+    fn share(start: u32, index: u32, parts: u32) -> Self {
+        let size = (1u64 << 32) / parts as u64;
+        let first = start.wrapping_add((size * index as u64) as u32);
+        // the last range takes what the division leaves over
+        let len = if index + 1 == parts { (1u64 << 32) - size * index as u64 } else { size };
+        NonceRange { first, len }
+    }
+}
+
+/// Hash up to `count` headers, moving to the next nonce of `range` after each. If one meets the
+/// difficulty, the header holds the solution and the number of hashes it took is returned; otherwise
+/// the header is left at the next nonce to try.
+fn search_nonce(header: &mut Header, count: u32, range: NonceRange) -> Option<u32> {
+    for tried in 1..=count {
+        if header.hash() <= header.difficulty {
+            return Some(tried);
+        }
+        if header.nonce.wrapping_sub(range.first) as u64 + 1 == range.len {
+            // the range is exhausted, a new timestamp gives fresh hashes without entering the other ranges
+            header.nonce = range.first;
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
+            header.timestamp = std::cmp::max(now, header.timestamp + 1);
+        } else {
+            header.nonce = header.nonce.wrapping_add(1);
+        }
+    }
+    None
+}
+
+/// Pick the transactions of a block template so as to collect as much in fees as the limits allow.
+/// Each sender's transactions must go in nonce order, so they are taken in packages: a prefix of the
+/// sender's pending transactions, and the package paying the most per byte is included first (a
+/// cheap transaction is worth including to unlock an expensive one behind it). The fees add up to at
+/// most `max_fees`, and `state` ends up as the state after the selected transactions.
+fn select_transactions(candidates: Vec<SignedTransaction>, state: &mut State, max_count: usize, max_size: usize,
+                       max_fees: u64) -> Vec<SignedTransaction> {
+    let mut queues: HashMap<Address, BTreeMap<u32, SignedTransaction>> = HashMap::new();
+    for tx in candidates {
+        let sender = Address::from_public_key_bytes(&tx.public_key);
+        queues.entry(sender).or_default().insert(tx.transaction.account_nonce, tx);
+    }
+
+    // executable chain of every sender: the consecutive nonces its balance can pay for, with their sizes
+    let mut chains: Vec<VecDeque<(SignedTransaction, usize)>> = Vec::new();
+    for (sender, mut queue) in queues {
+        if !state.exist(&sender) {
+            continue;
+        }
+        let mut nonce = state.get_nonce(&sender) + 1;
+        let mut balance = state.get_balance(&sender) as u64;
+        let mut chain = VecDeque::new();
+        while let Some(tx) = queue.remove(&nonce) {
+            if !verify(&tx.transaction, &tx.public_key, &tx.signature) || tx.cost() > balance {
+                break;
             }
+            balance -= tx.cost();
+            nonce += 1;
+            let tx_size = bincode::serialized_size(&tx).unwrap() as usize;
+            chain.push_back((tx, tx_size));
+        }
+        chains.push(chain);
+    }
 
-            if let OperatingState::Run(i) = self.operating_state {
-                if i != 0 {
-                    let interval = time::Duration::from_micros(i as u64);
-                    thread::sleep(interval);
+    let mut selected = Vec::new();
+    let (mut size, mut total_fees) = (0, 0u64);
+    loop {
+        // best package that still fits: (chain, length, fees, bytes)
+        let mut best: Option<(usize, usize, u64, usize)> = None;
+        for (i, chain) in chains.iter().enumerate() {
+            let (mut fees, mut bytes) = (0u64, 0usize);
+            for (len, (tx, tx_size)) in chain.iter().enumerate().take(max_count - selected.len()) {
+                if size + bytes + tx_size > max_size || total_fees + fees + tx.transaction.fee as u64 > max_fees {
+                    break;
+                }
+                fees += tx.transaction.fee as u64;
+                bytes += tx_size;
+                let better = match best {
+                    None => true,
+                    Some((_, _, best_fees, best_bytes)) => fees as u128 * best_bytes as u128 > best_fees as u128 * bytes as u128,
+                };
+                if better {
+                    best = Some((i, len + 1, fees, bytes));
                 }
             }
         }
+        let (i, len, _, _) = match best {
+            Some(best) => best,
+            None => break,
+        };
+        let package: Vec<(SignedTransaction, usize)> = chains[i].drain(..len).collect();
+        for (tx, tx_size) in package {
+            // an earlier package may have filled up the receiver, the rest of the chain has to wait
+            if validate_transaction(&tx, state).is_err() {
+                chains[i].clear();
+                break;
+            }
+            if !state.exist(&tx.transaction.receiver) {
+                state.add_account(tx.transaction.receiver, 0);
+            }
+            state.update_with_tx(&tx);
+            total_fees += tx.transaction.fee as u64;
+            selected.push(tx);
+            size += tx_size;
+        }
     }
+    selected
 }
 
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
@@ -283,7 +695,170 @@ impl Context {
 #[cfg(test)]
 mod test {
     use ntest::timeout;
-    use crate::types::hash::Hashable;
+    use crate::types::hash::{H256, Hashable};
+    use crate::types::address::Address;
+    use crate::types::params::ChainParams;
+    use crate::types::state::State;
+    use crate::types::transaction::generate_signed_transaction;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+    use crate::validation::validate_block;
+    use super::{EmptyBlockPolicy, OperatingState};
+
+    #[test]
+    fn select_by_fee_in_nonce_order() {
+        let (a1, a2, b1, b2, c2) = (
+            generate_signed_transaction(0, 1, 10, 1),
+            generate_signed_transaction(0, 2, 10, 100),
+            generate_signed_transaction(1, 1, 10, 50),
+            generate_signed_transaction(1, 2, 10, 40),
+            generate_signed_transaction(2, 2, 10, 1000),
+        );
+        let candidates = vec![a2.clone(), b2.clone(), c2, a1.clone(), b1.clone()];
+        let mut state = State::new(&ChainParams::default().premine);
+        let selected = super::select_transactions(candidates.clone(), &mut state, 3, usize::MAX, u64::MAX);
+        // the cheap a1 unlocks a2, c2 can never run without its first nonce
+        let hashes: Vec<_> = selected.iter().map(|tx| tx.hash()).collect();
+        assert_eq!(hashes, vec![a1.hash(), a2.hash(), b1.hash()]);
+        let sender = Address::from_public_key_bytes(&a1.public_key);
+        assert_eq!(state.get_nonce(&sender), 2);
+
+        // the size limit cuts packages too
+        let mut state = State::new(&ChainParams::default().premine);
+        let one_tx = bincode::serialized_size(&a1).unwrap() as usize;
+        let selected = super::select_transactions(candidates.clone(), &mut state, 10, one_tx, u64::MAX);
+        assert_eq!(selected.iter().map(|tx| tx.hash()).collect::<Vec<_>>(), vec![b1.hash()]);
+
+        // and so do the fees the coinbase can collect on top of the reward
+        let mut state = State::new(&ChainParams::default().premine);
+        let selected = super::select_transactions(candidates, &mut state, 10, usize::MAX, 60);
+        assert_eq!(selected.iter().map(|tx| tx.hash()).collect::<Vec<_>>(), vec![b1.hash(), a1.hash()]);
+    }
+
+    #[test]
+    fn nonce_ranges() {
+        use super::NonceRange;
+        // the shares are contiguous and cover the nonce space once, wrapping past u32::MAX
+        let start = u32::MAX - 10;
+        let shares: Vec<_> = (0..3).map(|index| NonceRange::share(start, index, 3)).collect();
+        assert_eq!(shares[0].first, start);
+        for pair in shares.windows(2) {
+            assert_eq!(pair[1].first, pair[0].first.wrapping_add(pair[0].len as u32));
+        }
+        assert_eq!(shares.iter().map(|range| range.len).sum::<u64>(), 1 << 32);
+
+        // an exhausted range starts over with a later timestamp instead of entering the next one
+        let (ctx, _handle, _finished_block_chan) = super::test_new();
+        let mut header = ctx.build_template().header;
+        header.difficulty = H256::default();
+        let range = NonceRange { first: header.nonce, len: 2 };
+        let timestamp = header.timestamp;
+        assert!(super::search_nonce(&mut header, 1, range).is_none());
+        assert_eq!(header.nonce, range.first.wrapping_add(1));
+        assert!(super::search_nonce(&mut header, 1, range).is_none());
+        assert_eq!(header.nonce, range.first);
+        assert!(header.timestamp > timestamp);
+    }
+
+    /// Compare hashes per second of the old search, which assembled a block for every attempt,
+    /// with iterating the nonce of a fixed template. Run with `--ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn hashrate() {
+        let (ctx, _handle, _finished_block_chan) = super::test_new();
+        for nonce in 1..=100 {
+            ctx.mempool.lock().unwrap().insert(&generate_signed_transaction(0, nonce, 10, 1)).unwrap();
+        }
+        let duration = Duration::from_secs(3);
+
+        let (start, mut hashes) = (Instant::now(), 0u64);
+        while start.elapsed() < duration {
+            let mut block = ctx.build_template();
+            // out of reach, only the cost of an attempt is measured
+            block.header.difficulty = H256::default();
+            let range = super::NonceRange::share(block.header.nonce, 0, 1);
+            assert!(super::search_nonce(&mut block.header, 1, range).is_none());
+            hashes += 1;
+        }
+        println!("template per attempt: {:.0} hashes/s", hashes as f64 / start.elapsed().as_secs_f64());
+
+        let mut block = ctx.build_template();
+        block.header.difficulty = H256::default();
+        let range = super::NonceRange::share(block.header.nonce, 0, 1);
+        let (start, mut hashes) = (Instant::now(), 0u64);
+        while start.elapsed() < duration {
+            assert!(super::search_nonce(&mut block.header, super::NONCES_PER_ROUND, range).is_none());
+            hashes += super::NONCES_PER_ROUND as u64;
+        }
+        println!("nonce search: {:.0} hashes/s", hashes as f64 / start.elapsed().as_secs_f64());
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn threads_mine_and_exit() {
+        let (mut ctx, handle, finished_block_chan) = super::test_new();
+        ctx.config.threads = 4;
+        ctx.mempool.lock().unwrap().insert(&generate_signed_transaction(0, 1, 10, 1)).unwrap();
+        let (blockchain, state_per_block) = (Arc::clone(&ctx.blockchain), Arc::clone(&ctx.state_per_block));
+        ctx.start();
+        handle.start(0);
+        let block = finished_block_chan.recv().unwrap();
+        assert!(validate_block(&block, &blockchain.lock().unwrap(), &state_per_block.lock().unwrap()).is_ok());
+        handle.exit();
+        while handle.status().state != OperatingState::ShutDown {
+            std::thread::yield_now();
+        }
+        // every thread is done with its last round
+        std::thread::sleep(Duration::from_millis(500));
+        let hashes = handle.status().hashes;
+        std::thread::sleep(Duration::from_millis(200));
+        assert_eq!(handle.status().hashes, hashes);
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn pause_and_status() {
+        let (ctx, handle, finished_block_chan) = super::test_new();
+        ctx.mempool.lock().unwrap().insert(&generate_signed_transaction(0, 1, 10, 1)).unwrap();
+        let genesis = ctx.blockchain.lock().unwrap().tip();
+        ctx.start();
+        handle.start(0);
+        let block = finished_block_chan.recv().unwrap();
+        handle.pause();
+        // wait for the pause to be applied
+        while handle.status().state != OperatingState::Paused {
+            std::thread::yield_now();
+        }
+        let status = handle.status();
+        assert_eq!(block.get_parent(), genesis);
+        assert_eq!(status.template_parent, None);
+        assert!(status.blocks_found >= 1);
+        assert!(status.hashes >= status.blocks_found);
+        assert!(status.hashrate > 0.0);
+    }
+
+    #[test]
+    fn parse_empty_block_policies() {
+        for policy in [EmptyBlockPolicy::Always, EmptyBlockPolicy::MinTransactions(3), EmptyBlockPolicy::Wait(500)] {
+            assert_eq!(policy.to_string().parse::<EmptyBlockPolicy>(), Ok(policy));
+        }
+        assert!("wait".parse::<EmptyBlockPolicy>().is_err());
+        assert!("min-txs:x".parse::<EmptyBlockPolicy>().is_err());
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn wait_for_transactions() {
+        let (mut ctx, handle, finished_block_chan) = super::test_new();
+        ctx.config.empty_blocks = EmptyBlockPolicy::Wait(300);
+        let started = Instant::now();
+        ctx.start();
+        handle.start(0);
+        // no transaction shows up, so a coinbase-only block is mined once the wait is over
+        let block = finished_block_chan.recv().unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(300));
+        assert_eq!(block.content.transactions.len(), 1);
+    }
 
     #[test]
     #[timeout(60000)]
@@ -0,0 +1,369 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::types::hash::H256;
+
+/// Decides when the blocks found by this node are announced. Every mined block goes into the
+/// local blockchain right away, so the miner keeps extending the heaviest branch it knows, withheld
+/// or not; only publishing to peers is up to the strategy. Like the fork choice, branches are
+/// compared by the total work up to their last block.
+pub trait MiningStrategy: Send {
+    fn name(&self) -> &'static str;
+    /// One of our blocks was mined, with `work` up to it. Returns the blocks to publish, parents first.
+    fn on_mined(&mut self, hash: H256, work: H256) -> Vec<H256>;
+    /// A block from the network was inserted, with `work` up to it. Returns the blocks to publish,
+    /// parents first.
+    fn on_received(&mut self, hash: H256, work: H256) -> Vec<H256>;
+}
+
+/// Publish every block as soon as it is found
+pub struct Honest;
+
+impl MiningStrategy for Honest {
+    fn name(&self) -> &'static str {
+        "honest"
+    }
+
+    fn on_mined(&mut self, hash: H256, _work: H256) -> Vec<H256> {
+        vec![hash]
+    }
+
+    fn on_received(&mut self, _hash: H256, _work: H256) -> Vec<H256> {
+        Vec::new()
+    }
+}
+
+/// Our withheld blocks, and how far the public chain is
+struct PrivateBranch {
+    /// Blocks not published yet with the total work up to them, lowest first
+    withheld: VecDeque<(H256, H256)>,
+    /// Total work up to our last block
+    work: H256,
+    /// Total work of the chain the network knows about
+    public_work: H256,
+    /// Everything is published and another branch has just as much work
+    racing: bool,
+}
+
+impl PrivateBranch {
+    /// Start from the public chain, whose tip has `public_work`
+    fn new(public_work: H256) -> Self {
+        PrivateBranch { withheld: VecDeque::new(), work: public_work, public_work, racing: false }
+    }
+
+    fn mined(&mut self, hash: H256, work: H256) {
+        self.withheld.push_back((hash, work));
+        self.work = work;
+        self.racing = false;
+    }
+
+    /// Update the public work, and give up the withheld blocks if the public chain got heavier
+    fn received(&mut self, work: H256) {
+        self.public_work = std::cmp::max(self.public_work, work);
+        if self.lead() < 0 {
+            self.withheld.clear();
+            self.racing = false;
+        }
+    }
+
+    /// -1 if the public chain is heavier, otherwise the number of withheld blocks beyond its work
+    fn lead(&self) -> isize {
+        if self.work < self.public_work {
+            return -1;
+        }
+        self.withheld.iter().filter(|(_, work)| *work > self.public_work).count() as isize
+    }
+
+    /// Publish the withheld blocks with at most `work` up to them
+    fn publish_up_to(&mut self, work: H256) -> Vec<H256> {
+        let mut published = Vec::new();
+        while let Some(&(hash, block_work)) = self.withheld.front() {
+            if block_work > work {
+                break;
+            }
+            self.withheld.pop_front();
+            self.public_work = std::cmp::max(self.public_work, block_work);
+            published.push(hash);
+        }
+        published
+    }
+
+    fn publish_all(&mut self) -> Vec<H256> {
+        self.publish_up_to(H256::MAX)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct SelfishConfig {
+    /// Publish the private branch once it is this many blocks ahead, 0 for never
+    pub max_lead: usize,
+}
+
+/// Selfish mining (Eyal and Sirer): withhold blocks, and release just enough of them to override
+/// or tie the public chain whenever it catches up.
+pub struct Selfish {
+    config: SelfishConfig,
+    branch: PrivateBranch,
+}
+
+impl Selfish {
+    /// `public_work` is the total work of the tip when mining starts
+    pub fn new(config: SelfishConfig, public_work: H256) -> Self {
+        Selfish { config, branch: PrivateBranch::new(public_work) }
+    }
+}
+
+impl MiningStrategy for Selfish {
+    fn name(&self) -> &'static str {
+        "selfish"
+    }
+
+    fn on_mined(&mut self, hash: H256, work: H256) -> Vec<H256> {
+        let racing = self.branch.racing;
+        self.branch.mined(hash, work);
+        // a block on our side of a tie wins the race
+        if racing || (self.config.max_lead > 0 && self.branch.lead() >= self.config.max_lead as isize) {
+            return self.branch.publish_all();
+        }
+        Vec::new()
+    }
+
+    fn on_received(&mut self, _hash: H256, work: H256) -> Vec<H256> {
+        self.branch.received(work);
+        if self.branch.withheld.is_empty() {
+            return Vec::new();
+        }
+        match self.branch.lead() {
+            // caught up: publish to tie and race
+            0 => {
+                self.branch.racing = true;
+                self.branch.publish_all()
+            }
+            // one block ahead: publish everything to override
+            1 => self.branch.publish_all(),
+            // comfortably ahead: match the public chain
+            _ => {
+                let public_work = self.branch.public_work;
+                self.branch.publish_up_to(public_work)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StubbornConfig {
+    /// Lead stubborn: when one block ahead, only match the public chain instead of overriding it
+    pub lead: bool,
+    /// Equal fork stubborn: keep a block found during a tie private instead of publishing it
+    pub equal_fork: bool,
+}
+
+impl Default for StubbornConfig {
+    fn default() -> Self {
+        StubbornConfig { lead: true, equal_fork: true }
+    }
+}
+
+/// Stubborn mining (Nayak et al.): selfish mining that takes more risk to keep its lead.
+pub struct Stubborn {
+    config: StubbornConfig,
+    branch: PrivateBranch,
+}
+
+impl Stubborn {
+    /// `public_work` is the total work of the tip when mining starts
+    pub fn new(config: StubbornConfig, public_work: H256) -> Self {
+        Stubborn { config, branch: PrivateBranch::new(public_work) }
+    }
+}
+
+impl MiningStrategy for Stubborn {
+    fn name(&self) -> &'static str {
+        "stubborn"
+    }
+
+    fn on_mined(&mut self, hash: H256, work: H256) -> Vec<H256> {
+        let racing = self.branch.racing;
+        self.branch.mined(hash, work);
+        if racing && !self.config.equal_fork {
+            return self.branch.publish_all();
+        }
+        Vec::new()
+    }
+
+    fn on_received(&mut self, _hash: H256, work: H256) -> Vec<H256> {
+        self.branch.received(work);
+        if self.branch.withheld.is_empty() {
+            return Vec::new();
+        }
+        match self.branch.lead() {
+            0 => {
+                self.branch.racing = true;
+                self.branch.publish_all()
+            }
+            1 if !self.config.lead => self.branch.publish_all(),
+            _ => {
+                let public_work = self.branch.public_work;
+                self.branch.publish_up_to(public_work)
+            }
+        }
+    }
+}
+
+/// Which strategy the miner worker follows, with its settings
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum StrategyConfig {
+    #[default]
+    Honest,
+    Selfish(SelfishConfig),
+    Stubborn(StubbornConfig),
+}
+
+impl StrategyConfig {
+    /// The strategy for mining on top of a tip with `public_work`
+    pub fn build(&self, public_work: H256) -> Box<dyn MiningStrategy> {
+        match *self {
+            StrategyConfig::Honest => Box::new(Honest),
+            StrategyConfig::Selfish(config) => Box::new(Selfish::new(config, public_work)),
+            StrategyConfig::Stubborn(config) => Box::new(Stubborn::new(config, public_work)),
+        }
+    }
+}
+
+impl FromStr for StrategyConfig {
+    type Err = String;
+
+    /// Parses `honest`, `selfish`, `selfish:<max lead>`, `stubborn` (both variants) or
+    /// `stubborn:<variants>` with a comma separated subset of `lead` and `fork`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split(':').collect();
+        match parts.as_slice() {
+            ["honest"] => Ok(StrategyConfig::Honest),
+            ["selfish"] => Ok(StrategyConfig::Selfish(SelfishConfig::default())),
+            ["selfish", max_lead] => max_lead
+                .parse()
+                .map(|max_lead| StrategyConfig::Selfish(SelfishConfig { max_lead }))
+                .map_err(|e| e.to_string()),
+            ["stubborn"] => Ok(StrategyConfig::Stubborn(StubbornConfig::default())),
+            ["stubborn", variants] => {
+                let mut config = StubbornConfig { lead: false, equal_fork: false };
+                for variant in variants.split(',') {
+                    match variant {
+                        "lead" => config.lead = true,
+                        "fork" => config.equal_fork = true,
+                        _ => return Err(format!("unknown stubborn variant {}", variant)),
+                    }
+                }
+                Ok(StrategyConfig::Stubborn(config))
+            }
+            _ => Err(format!("unknown mining strategy {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for StrategyConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StrategyConfig::Honest => write!(f, "honest"),
+            StrategyConfig::Selfish(config) => write!(f, "selfish:{}", config.max_lead),
+            StrategyConfig::Stubborn(config) => {
+                let variants: Vec<&str> = vec![(config.lead, "lead"), (config.equal_fork, "fork")]
+                    .into_iter()
+                    .filter(|(enabled, _)| *enabled)
+                    .map(|(_, name)| name)
+                    .collect();
+                write!(f, "stubborn:{}", variants.join(","))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash(n: u8) -> H256 {
+        [n; 32].into()
+    }
+
+    fn work(n: u64) -> H256 {
+        n.into()
+    }
+
+    #[test]
+    fn parse_strategies() {
+        for config in [
+            StrategyConfig::Honest,
+            StrategyConfig::Selfish(SelfishConfig { max_lead: 3 }),
+            StrategyConfig::Stubborn(StubbornConfig { lead: true, equal_fork: false }),
+            StrategyConfig::Stubborn(StubbornConfig::default()),
+        ] {
+            assert_eq!(config.to_string().parse::<StrategyConfig>(), Ok(config));
+        }
+        assert_eq!("selfish".parse::<StrategyConfig>(), Ok(StrategyConfig::Selfish(SelfishConfig { max_lead: 0 })));
+        assert!("stubborn:trail".parse::<StrategyConfig>().is_err());
+        assert!("greedy".parse::<StrategyConfig>().is_err());
+    }
+
+    #[test]
+    fn selfish_overrides_and_races() {
+        let mut selfish = Selfish::new(SelfishConfig::default(), work(0));
+        // two blocks ahead, the public chain finds one: match it, then override with the rest
+        assert!(selfish.on_mined(hash(1), work(1)).is_empty());
+        assert!(selfish.on_mined(hash(2), work(2)).is_empty());
+        assert!(selfish.on_mined(hash(3), work(3)).is_empty());
+        assert_eq!(selfish.on_received(hash(11), work(1)), vec![hash(1)]);
+        assert_eq!(selfish.on_received(hash(12), work(2)), vec![hash(2), hash(3)]);
+
+        // one block ahead and caught up: tie, then our next block settles the race
+        assert!(selfish.on_mined(hash(4), work(4)).is_empty());
+        assert_eq!(selfish.on_received(hash(14), work(4)), vec![hash(4)]);
+        assert_eq!(selfish.on_mined(hash(5), work(5)), vec![hash(5)]);
+
+        // overtaken: the private block is given up
+        assert!(selfish.on_mined(hash(6), work(6)).is_empty());
+        assert!(selfish.on_received(hash(16), work(6)).len() == 1);
+        assert!(selfish.on_received(hash(17), work(7)).is_empty());
+        assert!(selfish.branch.withheld.is_empty());
+    }
+
+    #[test]
+    fn selfish_max_lead() {
+        let mut selfish = Selfish::new(SelfishConfig { max_lead: 2 }, work(0));
+        assert!(selfish.on_mined(hash(1), work(1)).is_empty());
+        assert_eq!(selfish.on_mined(hash(2), work(2)), vec![hash(1), hash(2)]);
+    }
+
+    #[test]
+    fn branches_compared_by_work() {
+        // the chain already has work when mining starts: a block at the same work is a tie
+        let mut selfish = Selfish::new(SelfishConfig::default(), work(10));
+        assert!(selfish.on_mined(hash(1), work(12)).is_empty());
+        assert_eq!(selfish.on_received(hash(11), work(12)), vec![hash(1)]);
+        assert!(selfish.branch.racing);
+
+        // a single heavier public block beats two lighter withheld ones
+        let mut selfish = Selfish::new(SelfishConfig::default(), work(10));
+        assert!(selfish.on_mined(hash(2), work(11)).is_empty());
+        assert!(selfish.on_mined(hash(3), work(12)).is_empty());
+        assert!(selfish.on_received(hash(13), work(15)).is_empty());
+        assert!(selfish.branch.withheld.is_empty());
+    }
+
+    #[test]
+    fn stubborn_keeps_its_lead() {
+        let mut stubborn = Stubborn::new(StubbornConfig::default(), work(0));
+        assert!(stubborn.on_mined(hash(1), work(1)).is_empty());
+        assert!(stubborn.on_mined(hash(2), work(2)).is_empty());
+        // lead stubborn: one block ahead only matches
+        assert_eq!(stubborn.on_received(hash(11), work(1)), vec![hash(1)]);
+        assert_eq!(stubborn.on_received(hash(12), work(2)), vec![hash(2)]);
+        // equal fork stubborn: a block found during the tie stays private
+        assert!(stubborn.on_mined(hash(3), work(3)).is_empty());
+
+        let mut honest = Honest;
+        assert_eq!(honest.on_mined(hash(1), work(1)), vec![hash(1)]);
+        assert!(honest.on_received(hash(2), work(2)).is_empty());
+    }
+}
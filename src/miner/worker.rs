@@ -1,39 +1,52 @@
-use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
-use log::{debug, info};
-use crate::network::message::Message::{NewBlockHashes, self};
+use crossbeam::channel::{select, Receiver};
+use log::{debug, error, info, warn};
+use crate::network::message::Message;
 use crate::types::block::Block;
+use crate::types::hash::H256;
 use crate::types::mempool::Mempool;
-use crate::types::state::{StatePerBlock, State};
+use crate::types::state::StatePerBlock;
 use crate::network::server::Handle as ServerHandle;
+use crate::miner::Handle as MinerHandle;
+use crate::miner::strategy::MiningStrategy;
 use crate::blockchain::{Blockchain};
 use crate::types::hash::Hashable;
+use crate::validation::validate_block;
 use std::thread;
 use std::sync::{Arc, Mutex};
 
-#[derive(Clone)]
 pub struct Worker {
     server: ServerHandle,
+    miner: MinerHandle,
     finished_block_chan: Receiver<Block>,
     blockchain: Arc<Mutex<Blockchain>>,
+    mempool: Arc<Mutex<Mempool>>,
     state_per_block: Arc<Mutex<StatePerBlock>>,
+    /// Decides when mined blocks are published
+    strategy: Box<dyn MiningStrategy>,
 }
 
 impl Worker {
     pub fn new(
         server: &ServerHandle,
+        miner: &MinerHandle,
         finished_block_chan: Receiver<Block>,
         blockchain: &Arc<Mutex<Blockchain>>,
+        mempool: &Arc<Mutex<Mempool>>,
         state_per_block: &Arc<Mutex<StatePerBlock>>,
+        strategy: Box<dyn MiningStrategy>,
     ) -> Self {
         Self {
             server: server.clone(),
+            miner: miner.clone(),
             finished_block_chan,
             blockchain: Arc::clone(blockchain),
+            mempool: Arc::clone(mempool),
             state_per_block: Arc::clone(state_per_block),
+            strategy,
         }
     }
 
-    pub fn start(self) {
+    pub fn start(mut self) {
         thread::Builder::new()
             .name("miner-worker".to_string())
             .spawn(move || {
@@ -43,28 +56,93 @@ impl Worker {
         info!("Miner initialized into paused mode");
     }
 
-    fn worker_loop(&self) {
+    fn worker_loop(&mut self) {
+        let received_block_chan = self.miner.received_blocks();
         loop {
-            let _block = self
-                .finished_block_chan
-                .recv()
-                .expect("Receive finished block error");
-            {
-                // insert block
-                let mut blockchain = self.blockchain.lock().unwrap();
-                blockchain.insert(&_block);
-                debug!("Block {} succesfully mined; Broadcasting ...", _block.hash());
+            select! {
+                recv(self.finished_block_chan) -> block => {
+                    self.process_mined(block.expect("Receive finished block error"));
+                }
+                recv(received_block_chan) -> hash => {
+                    self.process_received(hash.expect("Receive network block error"));
+                }
             }
-            {
-                // update state per block (execute transactions)
-                let mut state_per_block = self.state_per_block.lock().unwrap();
-                state_per_block.update_with_block(&_block);
+        }
+    }
+
+    fn process_mined(&mut self, block: Block) {
+        let work;
+        {
+            let mut blockchain = self.blockchain.lock().unwrap();
+            let mut state_per_block = self.state_per_block.lock().unwrap();
+            // the template may have gone stale while mining, never insert an invalid block
+            if let Err(e) = validate_block(&block, &blockchain, &state_per_block) {
+                warn!("Dropping mined block {}: {}", block.hash(), e);
+                // the miner waits for a new template after each solution
+                self.miner.update();
+                return;
             }
 
-            self.server
-                    .broadcast(Message::NewBlockHashes(vec![_block.hash()])); // blocking operation
+            // insert block, withheld or not: the miner builds on the heaviest branch it knows
+            let reorg = match blockchain.insert(&block) {
+                Ok(reorg) => reorg,
+                Err(e) => {
+                    error!("Failed to persist mined block {}: {}", block.hash(), e);
+                    self.miner.update();
+                    return;
+                }
+            };
+            work = blockchain.total_work(&block.hash()).unwrap();
+            debug!("Block {} succesfully mined", block.hash());
+
+            // update state per block (execute transactions)
+            state_per_block.update_with_block(&block);
+
+            if let Some(reorg) = reorg {
+                self.mempool.lock().unwrap().follow_reorg(&blockchain, &state_per_block, &reorg);
+                self.miner.update();
+            }
+            self.log_revenue_share(&blockchain);
         }
+
+        let hashes = self.strategy.on_mined(block.hash(), work);
+        self.publish(hashes);
     }
 
+    fn process_received(&mut self, hash: H256) {
+        let work = match self.blockchain.lock().unwrap().total_work(&hash) {
+            Some(work) => work,
+            None => return,
+        };
+        let hashes = self.strategy.on_received(hash, work);
+        self.publish(hashes);
+    }
+
+    fn publish(&self, hashes: Vec<H256>) {
+        if hashes.is_empty() {
+            return;
+        }
+        debug!("Broadcasting {} mined blocks", hashes.len());
+        self.server.broadcast(Message::NewBlockHashes(hashes)); // blocking operation
+    }
 
-}
\ No newline at end of file
+    /// Log the part of the longest chain whose coinbase pays this node
+    fn log_revenue_share(&self, blockchain: &Blockchain) {
+        let chain = blockchain.all_blocks_in_longest_chain();
+        // the genesis block belongs to no one
+        let total = chain.len() - 1;
+        let address = self.miner.address();
+        let own = chain
+            .iter()
+            .filter_map(|hash| blockchain.get_block(hash)?.content.transactions.first())
+            .filter(|tx| tx.is_coinbase() && tx.transaction.receiver == address)
+            .count();
+        info!(
+            "Revenue share of the {} miner: {} of {} blocks ({:.1}%)",
+            self.strategy.name(),
+            own,
+            total,
+            100.0 * own as f64 / total.max(1) as f64
+        );
+    }
+}
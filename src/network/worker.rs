@@ -1,6 +1,7 @@
 use super::message::Message;
 use super::peer;
 use super::server::Handle as ServerHandle;
+use crate::miner::Handle as MinerHandle;
 use std::sync::{Arc, Mutex};
 use crate::types::hash::H256;
 use crate::types::hash::Hashable; 
@@ -11,6 +12,7 @@ use crate::types::block::{Block};
 use crate::types::transaction::{SignedTransaction, Transaction, verify};
 use crate::types::key_pair;
 use crate::types::address::Address;
+use crate::validation::{validate_block, BlockError};
 
 use std::collections::VecDeque;
 use std::collections::HashMap;
@@ -23,11 +25,16 @@ use std::thread;
 use super::peer::TestReceiver as PeerTestReceiver;
 #[cfg(any(test,test_utilities))]
 use super::server::TestReceiver as ServerTestReceiver;
+#[cfg(any(test,test_utilities))]
+use crate::blockchain::store::MemoryStore;
+#[cfg(any(test,test_utilities))]
+use crate::types::params::ChainParams;
 #[derive(Clone)]
 pub struct Worker {
     msg_chan: smol::channel::Receiver<(Vec<u8>, peer::Handle)>,
     num_worker: usize,
     server: ServerHandle,
+    miner: MinerHandle,
     blockchain: Arc<Mutex<Blockchain>>, 
     mempool: Arc<Mutex<Mempool>>,
     state_per_block: Arc<Mutex<StatePerBlock>>,
@@ -59,6 +66,7 @@ impl Worker {
         num_worker: usize,
         msg_src: smol::channel::Receiver<(Vec<u8>, peer::Handle)>,
         server: &ServerHandle,
+        miner: &MinerHandle,
         blockchain: &Arc<Mutex<Blockchain>>,
         mempool: &Arc<Mutex<Mempool>>,
         state_per_block: &Arc<Mutex<StatePerBlock>>,
@@ -67,6 +75,7 @@ impl Worker {
             msg_chan: msg_src,
             num_worker,
             server: server.clone(),
+            miner: miner.clone(),
             blockchain: Arc::clone(blockchain),
             mempool: Arc::clone(mempool),
             state_per_block: Arc::clone(state_per_block),
@@ -125,8 +134,7 @@ impl Worker {
                         let blockchain = self.blockchain.lock().unwrap();
                         block_vec  = hash_vec
                                     .into_iter()
-                                    .filter(|hash| blockchain.exist(&hash))
-                                    .map(|hash| blockchain.get_block(&hash))
+                                    .filter_map(|hash| blockchain.get_block(&hash).cloned())
                                     .collect();
                     }
                     if !block_vec.is_empty(){
@@ -146,64 +154,42 @@ impl Worker {
                         // Process the blocks in the queue
                         while let Some(blk) = block_queue.pop_front() {
                             
-                            // PoW validity check
                             debug!("Processing Block hash: {}", blk.hash());
-                            if blk.hash() > blk.get_difficulty() { // invalid block
-                                continue;
-                            }
-
-                            // Parent check for existence
-                            if !blockchain.exist(&blk.get_parent()) {
-                                //handling orphan block
-                                orphan_buffer.insert_child(&blk.clone());
-
-                                peer.write(Message::GetBlocks(vec![blk.get_parent()]));
-                                continue;
-                            }
-
-                            // Consistency of difficulty check
-                            let parent_difficulty = blockchain.get_block(&blk.get_parent()).get_difficulty();
-                            if parent_difficulty != blk.get_difficulty() {
+                            if blockchain.exist(&blk.hash()) {
                                 continue;
                             }
-
-                            // Check if the transactions in the block are invalid
-                            let mut invalid_tx = false;
-                            for tx in &blk.content.transactions {
-                                if !verify(&tx.transaction, &tx.public_key, &tx.signature) {
-                                    invalid_tx = true;
-                                    break;
+                            let mut state_per_block = self.state_per_block.lock().unwrap();
+                            match validate_block(&blk, &blockchain, &state_per_block) {
+                                Ok(()) => {}
+                                Err(BlockError::UnknownParent(parent)) => {
+                                    //handling orphan block
+                                    orphan_buffer.insert_child(&blk);
+                                    peer.write(Message::GetBlocks(vec![parent]));
+                                    continue;
                                 }
-                                let sender_account = Address::from_public_key_bytes(&tx.public_key);
-                                let value = tx.transaction.value;
-                                let nonce = tx.transaction.account_nonce;
-                                let cur_state = self.state_per_block.lock().unwrap().get_state(&blk.get_parent());
-                                if (cur_state.get_balance(&sender_account) < value) || 
-                                   (cur_state.get_nonce(&sender_account)+1 != nonce) {
-                                    invalid_tx = true;
-                                    break;
+                                Err(e) => {
+                                    debug!("Rejecting block {}: {}", blk.hash(), e);
+                                    continue;
                                 }
                             }
 
-                            if invalid_tx {
-                                continue;
-                            }
-
                             // Insert the block into the blockchain
-                            if !blockchain.exist(&blk.hash()) {
-
-                                blockchain.insert(&blk);
-                                self.state_per_block.lock().unwrap().update_with_block(&blk);
-
-                                // remove transactions in this block from mempool
-                                // update mempool
-                                {
-                                    let mut mempool = self.mempool.lock().unwrap();
-                                    for tx in &blk.content.transactions {
-                                        mempool.remove(&tx);
+                            {
+                                let reorg = match blockchain.insert(&blk) {
+                                    Ok(reorg) => reorg,
+                                    Err(e) => {
+                                        error!("Failed to persist block {}: {}", blk.hash(), e);
+                                        continue;
                                     }
+                                };
+                                state_per_block.update_with_block(&blk);
+
+                                if let Some(reorg) = reorg {
+                                    self.mempool.lock().unwrap().follow_reorg(&blockchain, &state_per_block, &reorg);
+                                    self.miner.update();
                                 }
 
+                                self.miner.block_received(blk.hash());
                                 new_blk_hashes.push(blk.hash());
                                 debug!("Block {} inserted", blk.hash());
                                 
@@ -257,26 +243,23 @@ impl Worker {
                     debug!("Receive Txs");
                     let mut new_tx_hashes = Vec::<H256>::new();
                     {
+                        let blockchain = self.blockchain.lock().unwrap();
+                        let tip_state = self.state_per_block.lock().unwrap().get_state(&blockchain.tip());
                         let mut mempool = self.mempool.lock().unwrap();
                         for signed_tx in tx_vec{
-                            // Check transaction validity
-                            if !verify(&signed_tx.transaction, &signed_tx.public_key, 
-                                    &signed_tx.signature) {
-                                debug!("Invalid Tx");
-                                // println!("Invalid Tx!!!");
-                                continue;
-                            }
-
-                            // Check if the transaction is already in the mempool
-                            if !mempool.exist(&signed_tx.hash()) {
-                                mempool.insert(&signed_tx);
-                                new_tx_hashes.push(signed_tx.hash());
-                                debug!("Tx {} inserted", signed_tx.hash());
+                            // only relay what we accept ourselves
+                            match mempool.admit(&signed_tx, &tip_state) {
+                                Ok(()) => {
+                                    new_tx_hashes.push(signed_tx.hash());
+                                    debug!("Tx {} inserted", signed_tx.hash());
+                                }
+                                Err(e) => debug!("Rejecting tx {}: {}", signed_tx.hash(), e),
                             }
                         }
                     }
 
                     if !new_tx_hashes.is_empty() {
+                        self.miner.update();
                         debug!("Broadcasting new tx hashes");
                         self.server.broadcast(Message::NewTransactionHashes(new_tx_hashes));
                     }
@@ -308,11 +291,19 @@ impl TestMsgSender {
 #[cfg(any(test,test_utilities))]
 /// returns two structs used by tests, and an ordered vector of hashes of all blocks in the blockchain
 fn generate_test_worker_and_start() -> (TestMsgSender, ServerTestReceiver, Vec<H256>) {
-    let blockchain = Blockchain::new();
+    // any hash meets the genesis target, so that random blocks are valid children
+    let params = ChainParams { initial_target: H256::MAX, max_target: H256::MAX, ..ChainParams::default() };
+    let blockchain = Blockchain::open(params.clone(), Box::new(MemoryStore)).unwrap();
+    let genesis_hash = blockchain.tip();
     let blockchain = Arc::new(Mutex::new(blockchain));
+    let mempool = Arc::new(Mutex::new(Mempool::new()));
+    let state_per_block = Arc::new(Mutex::new(StatePerBlock::new(&genesis_hash, &params)));
     let (server, server_receiver) = ServerHandle::new_for_test();
     let (test_msg_sender, msg_chan) = TestMsgSender::new();
-    let worker = Worker::new(1, msg_chan, &server, &blockchain);
+    // a paused miner keeps its control channel open
+    let (miner_ctx, miner, _finished_block_chan) = crate::miner::new(&blockchain, &mempool, &state_per_block, Address::random(), Default::default());
+    miner_ctx.start();
+    let worker = Worker::new(1, msg_chan, &server, &miner, &blockchain, &mempool, &state_per_block);
     worker.start(); 
     let all_blocks = blockchain.lock().unwrap().all_blocks_in_longest_chain();
     (test_msg_sender, server_receiver, all_blocks)
@@ -325,6 +316,7 @@ mod test {
     use ntest::timeout;
     use crate::types::block::generate_random_block;
     use crate::types::hash::Hashable;
+    use crate::types::transaction::generate_signed_transaction;
 
     use super::super::message::Message;
     use super::generate_test_worker_and_start;
@@ -369,6 +361,40 @@ mod test {
             panic!();
         }
     }
+    #[test]
+    #[timeout(60000)]
+    fn relay_only_admitted_transactions() {
+        let (test_msg_sender, server_receiver, _v) = generate_test_worker_and_start();
+        let (overspend, valid) = (generate_signed_transaction(0, 1, u32::MAX, 0), generate_signed_transaction(0, 1, 10, 0));
+        let _peer_receiver = test_msg_sender.send(Message::Transactions(vec![overspend, valid.clone()]));
+        let reply = server_receiver.recv().unwrap();
+        if let Message::NewTransactionHashes(v) = reply {
+            assert_eq!(v, vec![valid.hash()]);
+        } else {
+            panic!();
+        }
+    }
+    #[test]
+    #[timeout(60000)]
+    fn relay_replacements() {
+        let (test_msg_sender, server_receiver, _v) = generate_test_worker_and_start();
+        let signed = |nonce, fee| generate_signed_transaction(0, nonce, 10, fee);
+        let (original, underpriced, replacement, next) = (signed(1, 10), signed(1, 10), signed(1, 20), signed(2, 0));
+        let mut expected = Vec::new();
+        for tx in [original, underpriced, replacement, next] {
+            let _peer_receiver = test_msg_sender.send(Message::Transactions(vec![tx.clone()]));
+            expected.push(tx.hash());
+        }
+        // everything but the underpriced replacement is relayed, in order
+        expected.remove(1);
+        for hash in expected {
+            if let Message::NewTransactionHashes(v) = server_receiver.recv().unwrap() {
+                assert_eq!(v, vec![hash]);
+            } else {
+                panic!();
+            }
+        }
+    }
 }
 
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
\ No newline at end of file
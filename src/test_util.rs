@@ -0,0 +1,26 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// A fresh directory under the system temp dir, removed with everything in it when dropped, even
+/// if the test panics
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    pub fn new(prefix: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("{}-{}", prefix, rand::random::<u64>()));
+        fs::create_dir_all(&path).unwrap();
+        TempDir { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
@@ -25,6 +25,12 @@ impl std::convert::From<&[u8; 20]> for Address {
     }
 }
 
+impl std::convert::AsRef<[u8]> for Address {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
 impl std::convert::From<[u8; 20]> for Address {
     fn from(input: [u8; 20]) -> Address {
         Address(input)
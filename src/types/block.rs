@@ -68,12 +68,17 @@ impl Block {
     pub fn get_transactions(&self) -> Vec<SignedTransaction> {
         self.content.transactions.clone()
     }
+
+    /// Size of the serialized block in bytes
+    pub fn size(&self) -> usize {
+        bincode::serialized_size(&self).unwrap() as usize
+    }
 }
 
 #[cfg(any(test, test_utilities))]
 pub fn generate_random_block(parent: &H256) -> Block {
     let nonce = rand::random::<u32>(); // Generate random nonce
-    let content = Content(Vec::new()); // Empty content
+    let content = Content::new(Vec::new()); // Empty content
 
     let merkle_root = MerkleTree::new(&Vec::<H256>::new()).root(); // Empty Merkle tree
 
@@ -84,7 +89,7 @@ pub fn generate_random_block(parent: &H256) -> Block {
         parent: *parent,
         nonce,
         difficulty: difficulty, 
-        timestamp: std::time::SystemTime::now(), // Current system time
+        timestamp: std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_millis(), // Current system time
         merkle_root,
     };
 
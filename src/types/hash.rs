@@ -89,6 +89,14 @@ impl std::convert::From<ring::digest::Digest> for H256 {
     }
 }
 
+impl std::convert::From<u64> for H256 {
+    fn from(input: u64) -> H256 {
+        let mut buffer: [u8; 32] = [0; 32];
+        buffer[24..32].copy_from_slice(&input.to_be_bytes());
+        H256(buffer)
+    }
+}
+
 impl Ord for H256 {
     fn cmp(&self, other: &H256) -> std::cmp::Ordering {
         let self_higher = u128::from_be_bytes(self.0[0..16].try_into().unwrap());
@@ -109,6 +117,120 @@ impl PartialOrd for H256 {
     }
 }
 
+/// 256-bit unsigned arithmetic, treating the hash as a big endian integer (e.g. a PoW target).
+impl H256 {
+    pub const MAX: H256 = H256([0xff; 32]);
+
+    // most significant word first
+    fn to_words(self) -> [u64; 4] {
+        let mut words = [0u64; 4];
+        for (i, word) in words.iter_mut().enumerate() {
+            *word = u64::from_be_bytes(self.0[i * 8..i * 8 + 8].try_into().unwrap());
+        }
+        words
+    }
+
+    fn from_words(words: [u64; 4]) -> H256 {
+        let mut bytes = [0u8; 32];
+        for (i, word) in words.iter().enumerate() {
+            bytes[i * 8..i * 8 + 8].copy_from_slice(&word.to_be_bytes());
+        }
+        H256(bytes)
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.0.iter().all(|b| *b == 0)
+    }
+
+    pub fn overflowing_add(&self, other: &H256) -> (H256, bool) {
+        let (a, b) = (self.to_words(), other.to_words());
+        let mut sum = [0u64; 4];
+        let mut carry = false;
+        for i in (0..4).rev() {
+            let (s, c1) = a[i].overflowing_add(b[i]);
+            let (s, c2) = s.overflowing_add(carry as u64);
+            sum[i] = s;
+            carry = c1 || c2;
+        }
+        (H256::from_words(sum), carry)
+    }
+
+    pub fn saturating_add(&self, other: &H256) -> H256 {
+        match self.overflowing_add(other) {
+            (_, true) => H256::MAX,
+            (sum, false) => sum,
+        }
+    }
+
+    pub fn overflowing_sub(&self, other: &H256) -> (H256, bool) {
+        let (a, b) = (self.to_words(), other.to_words());
+        let mut diff = [0u64; 4];
+        let mut borrow = false;
+        for i in (0..4).rev() {
+            let (d, b1) = a[i].overflowing_sub(b[i]);
+            let (d, b2) = d.overflowing_sub(borrow as u64);
+            diff[i] = d;
+            borrow = b1 || b2;
+        }
+        (H256::from_words(diff), borrow)
+    }
+
+    /// Integer division, panics if `divisor` is zero
+    pub fn div(&self, divisor: &H256) -> H256 {
+        assert!(!divisor.is_zero(), "division by zero");
+        // shift-and-subtract long division, one bit at a time
+        let mut quotient = H256::default();
+        let mut remainder = H256::default();
+        for bit in 0..256 {
+            let (shifted, overflow) = remainder.overflowing_add(&remainder);
+            remainder = shifted;
+            if self.0[bit / 8] & (0x80 >> (bit % 8)) != 0 {
+                remainder.0[31] |= 1;
+            }
+            if overflow || remainder >= *divisor {
+                remainder = remainder.overflowing_sub(divisor).0;
+                quotient.0[bit / 8] |= 0x80 >> (bit % 8);
+            }
+        }
+        quotient
+    }
+
+    pub fn overflowing_mul_u64(&self, factor: u64) -> (H256, bool) {
+        let a = self.to_words();
+        let mut product = [0u64; 4];
+        let mut carry = 0u128;
+        for i in (0..4).rev() {
+            let p = a[i] as u128 * factor as u128 + carry;
+            product[i] = p as u64;
+            carry = p >> 64;
+        }
+        (H256::from_words(product), carry != 0)
+    }
+
+    /// Multiply by `numerator / denominator` (e.g. to rescale a target), saturating at `H256::MAX`
+    pub fn scale(&self, numerator: u64, denominator: u64) -> H256 {
+        // divide first when the product would not fit, losing only the low bits
+        match self.overflowing_mul_u64(numerator) {
+            (product, false) => product.div(&H256::from(denominator)),
+            (_, true) => match self.div(&H256::from(denominator)).overflowing_mul_u64(numerator) {
+                (scaled, false) => scaled,
+                (_, true) => H256::MAX,
+            },
+        }
+    }
+
+    /// Expected number of hashes needed to find a block with this target, i.e. 2^256 / (target + 1)
+    pub fn work(&self) -> H256 {
+        if *self == H256::MAX {
+            return H256::from(1u64);
+        }
+        // 2^256 does not fit, but 2^256 / (t + 1) == (2^256 - 1 - t) / (t + 1) + 1
+        let not_target = H256::MAX.overflowing_sub(self).0;
+        let plus_one = self.overflowing_add(&H256::from(1u64)).0;
+        not_target.div(&plus_one).saturating_add(&H256::from(1u64))
+    }
+}
+
 #[cfg(any(test, test_utilities))]
 pub fn generate_random_hash() -> H256 {
     let mut rng = rand::thread_rng();
@@ -116,4 +238,45 @@ pub fn generate_random_hash() -> H256 {
     let mut raw_bytes = [0; 32];
     raw_bytes.copy_from_slice(&random_bytes);
     (&raw_bytes).into()
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::H256;
+
+    #[test]
+    fn arithmetic() {
+        let a: H256 = hex!("00000000000000000000000000000001ffffffffffffffffffffffffffffffff").into();
+        let b = H256::from(1u64);
+        let sum: H256 = hex!("0000000000000000000000000000000200000000000000000000000000000000").into();
+        assert_eq!(a.overflowing_add(&b), (sum, false));
+        assert_eq!(sum.overflowing_sub(&b), (a, false));
+        assert_eq!(H256::MAX.overflowing_add(&b), (H256::default(), true));
+        assert_eq!(H256::default().overflowing_sub(&b), (H256::MAX, true));
+        assert_eq!(sum.div(&H256::from(4u64)), hex!("0000000000000000000000000000000080000000000000000000000000000000").into());
+        assert_eq!(H256::from(100u64).div(&H256::from(7u64)), H256::from(14u64));
+        assert_eq!(a.overflowing_mul_u64(2), (hex!("00000000000000000000000000000003fffffffffffffffffffffffffffffffe").into(), false));
+        assert!(H256::MAX.overflowing_mul_u64(2).1);
+    }
+
+    #[test]
+    fn scale() {
+        let target: H256 = hex!("000fffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff").into();
+        let quarter: H256 = hex!("0003ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff").into();
+        assert_eq!(target.scale(1, 4), quarter);
+        assert_eq!(quarter.scale(4, 1), target.overflowing_sub(&H256::from(3u64)).0);
+        assert_eq!(target.scale(3, 3), target);
+        assert_eq!(H256::MAX.scale(4, 1), H256::MAX);
+        let half_max: H256 = hex!("7fffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff").into();
+        assert_eq!(H256::MAX.scale(1, 2), half_max);
+    }
+
+    #[test]
+    fn work() {
+        assert_eq!(H256::MAX.work(), H256::from(1u64));
+        let target: H256 = hex!("0fffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff").into();
+        assert_eq!(target.work(), H256::from(16u64));
+        let target: H256 = hex!("000fffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff").into();
+        assert_eq!(target.work(), H256::from(4096u64));
+    }
+}
@@ -1,3 +1,7 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use crate::blockchain::store::replace_file;
 use ring::rand;
 use ring::signature::Ed25519KeyPair;
 
@@ -7,3 +11,72 @@ pub fn random() -> Ed25519KeyPair {
     let pkcs8_bytes = Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
     Ed25519KeyPair::from_pkcs8(pkcs8_bytes.as_ref().into()).unwrap()
 }
+
+/// Parse a key seed: 64 hex digits, or a single byte `N` standing for `N` repeated 32 times
+/// (the seeds of the default testnet accounts are 0, 1 and 2).
+pub fn parse_seed(s: &str) -> Result<[u8; 32], String> {
+    if let Ok(byte) = s.parse::<u8>() {
+        return Ok([byte; 32]);
+    }
+    let bytes = hex::decode(s).map_err(|e| e.to_string())?;
+    if bytes.len() != 32 {
+        return Err(format!("expected 32 bytes, got {}", bytes.len()));
+    }
+    let mut seed = [0u8; 32];
+    seed.copy_from_slice(&bytes);
+    Ok(seed)
+}
+
+/// Deterministic key pair from a 32-byte seed.
+pub fn from_seed(seed: &[u8; 32]) -> Ed25519KeyPair {
+    Ed25519KeyPair::from_seed_unchecked(seed).unwrap()
+}
+
+/// Load the PKCS#8 encoded key pair stored in `path`.
+/// If the file does not exist, a fresh key pair is generated and saved there.
+pub fn load_or_generate(path: &Path) -> io::Result<Ed25519KeyPair> {
+    if path.exists() {
+        let pkcs8_bytes = fs::read(path)?;
+        return Ed25519KeyPair::from_pkcs8(&pkcs8_bytes)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()));
+    }
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let rng = rand::SystemRandom::new();
+    let pkcs8_bytes = Ed25519KeyPair::generate_pkcs8(&rng)
+        .map_err(|_| io::Error::new(io::ErrorKind::Other, "key generation failed"))?;
+    // only readable by the owner, and never left half written
+    replace_file(path, pkcs8_bytes.as_ref(), 0o600)?;
+    Ed25519KeyPair::from_pkcs8(pkcs8_bytes.as_ref())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::signature::KeyPair;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn parse_seeds() {
+        assert_eq!(parse_seed("2"), Ok([2; 32]));
+        assert_eq!(parse_seed(&"ab".repeat(32)), Ok([0xab; 32]));
+        assert!(parse_seed("abcd").is_err());
+        assert!(parse_seed("not a seed").is_err());
+    }
+
+    #[test]
+    fn key_file_is_generated_once() {
+        let dir = TempDir::new("key-test");
+        let path = dir.path().join("keys").join("node.key");
+        let generated = load_or_generate(&path).unwrap();
+        let loaded = load_or_generate(&path).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+        assert_eq!(generated.public_key().as_ref(), loaded.public_key().as_ref());
+    }
+}
@@ -1,19 +1,153 @@
 use super::{
+    address::Address,
+    block::Block,
     hash::{Hashable, H256},
+    state::{State, StatePerBlock},
     transaction::SignedTransaction,
 };
+use crate::blockchain::{Blockchain, Reorg};
+use crate::blockchain::store::replace_file;
+use crate::validation::{validate_pending_transaction, TxError};
 
-use std::collections::HashMap;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap, HashMap, HashSet};
+use std::collections::btree_map;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde::Serialize;
+
+/// Why the mempool refuses a transaction
+#[derive(Debug, Clone, PartialEq)]
+pub enum Rejection {
+    AlreadyKnown,
+    /// Coinbases only exist inside blocks
+    Coinbase,
+    /// The transaction cannot be executed on top of the tip
+    Invalid(TxError),
+    /// Another pending transaction of the sender has this nonce, and this one does not pay
+    /// enough more to replace it
+    ReplacementUnderpriced { required: u64, got: u32 },
+    /// The pool is full of transactions with a higher priority
+    PoolFull,
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Rejection::AlreadyKnown => write!(f, "already known"),
+            Rejection::Coinbase => write!(f, "coinbase outside a block"),
+            Rejection::Invalid(e) => write!(f, "{}", e),
+            Rejection::ReplacementUnderpriced { required, got } => {
+                write!(f, "replacement fee {} below the required {}", got, required)
+            }
+            Rejection::PoolFull => write!(f, "mempool full"),
+        }
+    }
+}
+
+/// Pending transactions of one sender, by nonce
+#[derive(Debug, Default, Clone)]
+struct SenderQueue {
+    /// Consecutive nonces right after the account nonce, executable in order on top of the tip
+    ready: BTreeMap<u32, H256>,
+    /// Transactions waiting for a missing nonce before them
+    future: BTreeMap<u32, H256>,
+}
+
+impl SenderQueue {
+    fn is_empty(&self) -> bool {
+        self.ready.is_empty() && self.future.is_empty()
+    }
+
+    /// Move future transactions to the ready set as long as they follow it without a gap
+    fn promote(&mut self, account_nonce: u32) {
+        let mut next = self.ready.keys().next_back().map_or(account_nonce, |n| *n) + 1;
+        while let Some(hash) = self.future.remove(&next) {
+            self.ready.insert(next, hash);
+            next += 1;
+        }
+    }
+}
+
+/// Size limits of the mempool
+#[derive(Debug, Clone)]
+pub struct MempoolConfig {
+    pub max_transactions: usize,
+    /// Largest total size of the serialized transactions
+    pub max_bytes: usize,
+    /// Transactions are dropped after waiting this long (ms)
+    pub ttl: u128,
+    /// A transaction replaces a pending one with the same sender and nonce only if its fee is
+    /// higher by at least this percentage (and at least by one)
+    pub min_fee_bump: u32,
+}
+
+impl Default for MempoolConfig {
+    fn default() -> Self {
+        MempoolConfig {
+            max_transactions: 10_000,
+            max_bytes: 5_000_000,
+            ttl: 3 * 60 * 60 * 1000,
+            min_fee_bump: 10,
+        }
+    }
+}
+
+/// How many transactions left the pool without being mined
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct MempoolStats {
+    /// Pushed out by transactions with a higher priority
+    pub evicted: u64,
+    /// Waited longer than the time-to-live
+    pub expired: u64,
+    /// Replaced by a transaction with the same sender and nonce and a higher fee
+    pub replaced: u64,
+    /// No longer valid on top of the tip
+    pub invalidated: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    /// When the transaction entered the pool (ms since the epoch)
+    arrival: u128,
+    size: usize,
+}
 
 #[derive(Debug, Default, Clone)]
 pub struct Mempool {
     pub transactions: HashMap<H256, SignedTransaction>,
+    entries: HashMap<H256, Entry>,
+    senders: HashMap<Address, SenderQueue>,
+    /// Nonce of every account at the tip, as of the last `revalidate`
+    account_nonces: HashMap<Address, u32>,
+    /// Total size of the transactions
+    bytes: usize,
+    config: MempoolConfig,
+    stats: MempoolStats,
+}
+
+fn now() -> u128 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis()
 }
 
 impl Mempool {
     pub fn new() -> Self {
-        let transactions: HashMap <H256, SignedTransaction> = HashMap::new();
-        Mempool { transactions }
+        Self::with_config(MempoolConfig::default())
+    }
+
+    pub fn with_config(config: MempoolConfig) -> Self {
+        Mempool {
+            transactions: HashMap::new(),
+            entries: HashMap::new(),
+            senders: HashMap::new(),
+            account_nonces: HashMap::new(),
+            bytes: 0,
+            config,
+            stats: MempoolStats::default(),
+        }
     }
 
     pub fn all_transactions(&self) -> Vec<SignedTransaction> {
@@ -24,20 +158,579 @@ impl Mempool {
         ret_vec
     }
 
-    pub fn insert(&mut self, tx: &SignedTransaction) {
-        self.transactions.insert(tx.hash(), tx.clone());
+    fn account_nonce(&self, sender: &Address) -> u32 {
+        self.account_nonces.get(sender).copied().unwrap_or(0)
+    }
+
+    /// Add a transaction to its sender's queue, as ready if it directly follows the sender's
+    /// account nonce or ready transactions, as future otherwise. A pending transaction with the
+    /// same nonce is replaced if the new one pays enough more, see `MempoolConfig::min_fee_bump`.
+    /// Only the nonce is checked against the chain, see `admit` for full checks.
+    pub fn insert(&mut self, tx: &SignedTransaction) -> Result<(), Rejection> {
+        self.insert_at(tx, now())
     }
 
+    fn insert_at(&mut self, tx: &SignedTransaction, arrival: u128) -> Result<(), Rejection> {
+        let hash = tx.hash();
+        if tx.is_coinbase() {
+            return Err(Rejection::Coinbase);
+        }
+        if self.exist(&hash) {
+            return Err(Rejection::AlreadyKnown);
+        }
+        let sender = Address::from_public_key_bytes(&tx.public_key);
+        let nonce = tx.transaction.account_nonce;
+        let account_nonce = self.account_nonce(&sender);
+        if nonce <= account_nonce {
+            return Err(Rejection::Invalid(TxError::BadNonce { expected: account_nonce + 1, got: nonce }));
+        }
+        let queue = self.senders.entry(sender).or_default();
+        let pending = queue.ready.get(&nonce).or_else(|| queue.future.get(&nonce)).copied();
+        if let Some(old) = pending {
+            let old_fee = self.transactions[&old].transaction.fee as u64;
+            let bump = std::cmp::max(1, (old_fee * self.config.min_fee_bump as u64).div_ceil(100));
+            if (tx.transaction.fee as u64) < old_fee + bump {
+                return Err(Rejection::ReplacementUnderpriced { required: old_fee + bump, got: tx.transaction.fee });
+            }
+            // take the old one's place, so that the sender's queue is unchanged
+            if let Some(slot) = queue.ready.get_mut(&nonce) {
+                *slot = hash;
+            } else {
+                queue.future.insert(nonce, hash);
+            }
+            self.transactions.remove(&old);
+            self.bytes -= self.entries.remove(&old).unwrap().size;
+            self.stats.replaced += 1;
+        } else {
+            queue.future.insert(nonce, hash);
+            queue.promote(account_nonce);
+        }
+        let size = bincode::serialized_size(tx).unwrap() as usize;
+        self.transactions.insert(hash, tx.clone());
+        self.entries.insert(hash, Entry { arrival, size });
+        self.bytes += size;
+
+        // make room by dropping the lowest priority transactions, possibly the new one
+        while self.transactions.len() > self.config.max_transactions || self.bytes > self.config.max_bytes {
+            let victim = self.lowest_priority().unwrap();
+            self.remove(&self.transactions[&victim].clone());
+            if victim == hash {
+                return Err(Rejection::PoolFull);
+            }
+            self.stats.evicted += 1;
+        }
+        Ok(())
+    }
+
+    /// Transaction to evict first: the one with the lowest fee (the oldest one among equal fees)
+    /// among the last transactions of every sender, so that no queue gets a gap
+    fn lowest_priority(&self) -> Option<H256> {
+        self.senders
+            .values()
+            .filter_map(|queue| queue.future.values().next_back().or_else(|| queue.ready.values().next_back()))
+            .min_by_key(|hash| (self.transactions[*hash].transaction.fee, self.entries[*hash].arrival))
+            .copied()
+    }
+
+    /// Drop the transactions that waited longer than the time-to-live
+    pub fn expire(&mut self) {
+        self.expire_at(now());
+    }
+
+    fn expire_at(&mut self, now: u128) {
+        let expired: Vec<SignedTransaction> = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| now.saturating_sub(entry.arrival) > self.config.ttl)
+            .map(|(hash, _)| self.transactions[hash].clone())
+            .collect();
+        for tx in expired.iter() {
+            self.remove(tx);
+        }
+        self.stats.expired += expired.len() as u64;
+    }
+
+    /// Insert a transaction received from outside (e.g. a peer) once it is checked against the
+    /// state of the tip
+    pub fn admit(&mut self, tx: &SignedTransaction, tip_state: &State) -> Result<(), Rejection> {
+        self.admit_at(tx, tip_state, now())
+    }
+
+    fn admit_at(&mut self, tx: &SignedTransaction, tip_state: &State, arrival: u128) -> Result<(), Rejection> {
+        if tx.is_coinbase() {
+            return Err(Rejection::Coinbase);
+        }
+        if self.exist(&tx.hash()) {
+            return Err(Rejection::AlreadyKnown);
+        }
+        validate_pending_transaction(tx, tip_state).map_err(Rejection::Invalid)?;
+        self.insert_at(tx, arrival)
+    }
+
+    /// Write every transaction, with its arrival time, to `path`.
+    /// The file is replaced atomically and durably, so a crash while saving keeps the previous dump.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let dump: Vec<(u128, &SignedTransaction)> = self
+            .transactions
+            .iter()
+            .map(|(hash, tx)| (self.entries[hash].arrival, tx))
+            .collect();
+        let bytes = bincode::serialize(&dump).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        replace_file(path, &bytes, 0o644)
+    }
+
+    /// Load the transactions saved in `path` back into the pool, keeping their arrival times.
+    /// They go through the same checks as new transactions against `tip_state`, since the chain
+    /// may have moved on. Returns how many were restored.
+    pub fn restore(&mut self, path: &Path, tip_state: &State) -> io::Result<usize> {
+        if !path.exists() {
+            return Ok(0);
+        }
+        let bytes = fs::read(path)?;
+        let mut dump: Vec<(u128, SignedTransaction)> = bincode::deserialize(&bytes)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        // nonce order avoids needless future entries and replacements
+        dump.sort_by_key(|(_, tx)| tx.transaction.account_nonce);
+        for (arrival, tx) in dump.iter() {
+            let _ = self.admit_at(tx, tip_state, *arrival);
+        }
+        self.revalidate(tip_state);
+        Ok(self.len())
+    }
+
+    /// Remove a transaction; the sender's ready transactions after it go back to the future set
     pub fn remove(&mut self, tx: &SignedTransaction) {
-        self.transactions.remove(&tx.hash());
+        let hash = tx.hash();
+        if self.transactions.remove(&hash).is_none() {
+            return;
+        }
+        self.bytes -= self.entries.remove(&hash).unwrap().size;
+        let sender = Address::from_public_key_bytes(&tx.public_key);
+        let nonce = tx.transaction.account_nonce;
+        let queue = self.senders.get_mut(&sender).unwrap();
+        if queue.ready.remove(&nonce).is_some() {
+            let after = queue.ready.split_off(&nonce);
+            queue.future.extend(after);
+        } else {
+            queue.future.remove(&nonce);
+        }
+        if queue.is_empty() {
+            self.senders.remove(&sender);
+        }
     }
 
     pub fn exist(&self, hash: &H256) -> bool {
         self.transactions.contains_key(hash)
     }
 
+    pub fn len(&self) -> usize {
+        self.transactions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.transactions.is_empty()
+    }
+
+    /// Total size of the transactions in bytes
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    pub fn stats(&self) -> &MempoolStats {
+        &self.stats
+    }
+
     pub fn get_tx(&self, hash: &H256) -> SignedTransaction {
         self.transactions.get(hash).unwrap().clone()
     }
 
+    /// Number of transactions that can be executed on top of the tip
+    pub fn ready_len(&self) -> usize {
+        self.senders.values().map(|queue| queue.ready.len()).sum()
+    }
+
+    /// Number of transactions waiting for a missing nonce
+    pub fn future_len(&self) -> usize {
+        self.senders.values().map(|queue| queue.future.len()).sum()
+    }
+
+    /// Check the pool against the state of a new tip: transactions with a used nonce, an unknown
+    /// sender or a cost the sender can no longer pay leave the pool, and so do expired ones.
+    pub fn revalidate(&mut self, tip_state: &State) {
+        self.update_account_nonces(tip_state);
+
+        let mut invalid = Vec::new();
+        for (sender, queue) in self.senders.iter() {
+            if !tip_state.exist(sender) {
+                invalid.extend(queue.ready.values().chain(queue.future.values()).copied());
+                continue;
+            }
+            // ready transactions pay one after the other, future ones at least on their own
+            let mut balance = tip_state.get_balance(sender) as u64;
+            for hash in queue.ready.values() {
+                let cost = self.transactions[hash].cost();
+                if cost > balance {
+                    invalid.push(*hash);
+                } else {
+                    balance -= cost;
+                }
+            }
+            let balance = tip_state.get_balance(sender) as u64;
+            invalid.extend(queue.future.values().filter(|hash| self.transactions[*hash].cost() > balance));
+        }
+        for hash in invalid.iter() {
+            let tx = self.transactions[hash].clone();
+            self.remove(&tx);
+        }
+        self.stats.invalidated += invalid.len() as u64;
+        self.expire();
+    }
+
+    /// Follow the account nonces of a new tip: transactions with a used nonce leave the pool, and
+    /// the queues are split again into ready and future transactions.
+    fn update_account_nonces(&mut self, tip_state: &State) {
+        self.account_nonces = tip_state.account_states.iter().map(|(address, account)| (*address, account.nonce)).collect();
+        let mut used = Vec::new();
+        for (sender, queue) in self.senders.iter_mut() {
+            let account_nonce = self.account_nonces.get(sender).copied().unwrap_or(0);
+            let mut pending = std::mem::take(&mut queue.ready);
+            pending.append(&mut queue.future);
+            queue.future = pending.split_off(&(account_nonce + 1));
+            used.extend(pending.into_values());
+            queue.promote(account_nonce);
+        }
+        for hash in used {
+            self.transactions.remove(&hash);
+            self.bytes -= self.entries.remove(&hash).unwrap().size;
+        }
+        self.senders.retain(|_, queue| !queue.is_empty());
+    }
+
+    /// Ready transactions in an order they can be executed in: each sender's transactions in
+    /// nonce order, interleaved so that the highest fee among the senders' next ones comes first.
+    pub fn executable(&self) -> Executable<'_> {
+        let mut queues: Vec<btree_map::Values<'_, u32, H256>> = self.senders.values().map(|queue| queue.ready.values()).collect();
+        let mut heads = BinaryHeap::new();
+        for (i, queue) in queues.iter_mut().enumerate() {
+            if let Some(hash) = queue.next() {
+                heads.push((self.transactions[hash].transaction.fee, Reverse(i), *hash));
+            }
+        }
+        Executable { transactions: &self.transactions, queues, heads }
+    }
+
+    /// Follow a move of the tip: transactions of the `connected` blocks are now confirmed and leave
+    /// the pool, those of the `disconnected` blocks come back if they are still valid on top of
+    /// `tip_state`, the state of the new tip, and the whole pool is revalidated against it.
+    pub fn apply_reorg(&mut self, disconnected: &[Block], connected: &[Block], tip_state: &State) {
+        let mut confirmed = HashSet::new();
+        for block in connected {
+            for tx in block.content.transactions.iter() {
+                self.remove(tx);
+                confirmed.insert(tx.hash());
+            }
+        }
+        self.revalidate(tip_state);
+        for block in disconnected {
+            for tx in block.content.transactions.iter() {
+                if confirmed.contains(&tx.hash()) {
+                    continue;
+                }
+                // those no longer valid are simply dropped
+                let _ = self.admit(tx, tip_state);
+            }
+        }
+    }
+
+    /// `apply_reorg` for a tip move reported by `Blockchain::insert`: drop the confirmed
+    /// transactions, put back the orphaned ones and revalidate the pool against the new tip.
+    pub fn follow_reorg(&mut self, blockchain: &Blockchain, state_per_block: &StatePerBlock, reorg: &Reorg) {
+        let disconnected: Vec<Block> = reorg.disconnected.iter().filter_map(|h| blockchain.get_block(h).cloned()).collect();
+        let connected: Vec<Block> = reorg.connected.iter().filter_map(|h| blockchain.get_block(h).cloned()).collect();
+        let tip_state = state_per_block.get_state(&blockchain.tip());
+        self.apply_reorg(&disconnected, &connected, &tip_state);
+    }
+
+}
+
+/// Iterator returned by `Mempool::executable`
+pub struct Executable<'a> {
+    transactions: &'a HashMap<H256, SignedTransaction>,
+    queues: Vec<btree_map::Values<'a, u32, H256>>,
+    /// Next transaction of every sender: (fee, sender index, hash)
+    heads: BinaryHeap<(u32, Reverse<usize>, H256)>,
+}
+
+impl<'a> Iterator for Executable<'a> {
+    type Item = &'a SignedTransaction;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (_, Reverse(i), hash) = self.heads.pop()?;
+        if let Some(next) = self.queues[i].next() {
+            self.heads.push((self.transactions[next].transaction.fee, Reverse(i), *next));
+        }
+        Some(&self.transactions[&hash])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::block::generate_random_block;
+    use crate::types::params::ChainParams;
+    use crate::types::transaction::generate_signed_transaction;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn reorg_reinjects_disconnected_transactions() {
+        let state = State::new(&ChainParams::default().premine);
+        let (tx1, tx2, stale, both) = (
+            generate_signed_transaction(0, 1, 10, 0),
+            generate_signed_transaction(1, 1, 10, 0),
+            generate_signed_transaction(2, 0, 10, 0),
+            generate_signed_transaction(2, 1, 10, 0),
+        );
+        let mut old_block = generate_random_block(&H256::default());
+        old_block.content.transactions = vec![tx1.clone(), tx2.clone(), stale.clone(), both.clone()];
+        let mut new_block = generate_random_block(&H256::default());
+        new_block.content.transactions = vec![both.clone()];
+
+        let mut mempool = Mempool::new();
+        mempool.insert(&both).unwrap();
+        mempool.apply_reorg(&[old_block], &[new_block], &state);
+        assert!(mempool.exist(&tx1.hash()));
+        assert!(mempool.exist(&tx2.hash()));
+        // nonce already used, and confirmed again by the new chain
+        assert!(!mempool.exist(&stale.hash()));
+        assert!(!mempool.exist(&both.hash()));
+    }
+
+    #[test]
+    fn future_transactions_are_promoted() {
+        let mut mempool = Mempool::new();
+        let (n1, n2, n3) = (
+            generate_signed_transaction(0, 1, 10, 0),
+            generate_signed_transaction(0, 2, 10, 0),
+            generate_signed_transaction(0, 3, 10, 0),
+        );
+        assert_eq!(mempool.insert(&n3), Ok(()));
+        assert_eq!(mempool.insert(&n2), Ok(()));
+        assert_eq!((mempool.ready_len(), mempool.future_len()), (0, 2));
+        // filling the gap makes the whole queue ready
+        assert_eq!(mempool.insert(&n1), Ok(()));
+        assert_eq!((mempool.ready_len(), mempool.future_len()), (3, 0));
+        // a known transaction is not added twice
+        assert_eq!(mempool.insert(&n1), Err(Rejection::AlreadyKnown));
+
+        // removing one in the middle sends the ones after it back to the future set
+        mempool.remove(&n2);
+        assert_eq!((mempool.ready_len(), mempool.future_len()), (1, 1));
+    }
+
+    #[test]
+    fn follow_account_nonces() {
+        let mut mempool = Mempool::new();
+        let (n1, n2, n3) = (
+            generate_signed_transaction(0, 1, 10, 0),
+            generate_signed_transaction(0, 2, 10, 0),
+            generate_signed_transaction(0, 3, 10, 0),
+        );
+        mempool.insert(&n2).unwrap();
+        mempool.insert(&n3).unwrap();
+        let mut state = State::new(&ChainParams::default().premine);
+        let sender = Address::from_public_key_bytes(&n1.public_key);
+        // n1 got confirmed: n2 and n3 are now ready, and n1 can no longer enter
+        state.account_states.get_mut(&sender).unwrap().nonce = 1;
+        mempool.update_account_nonces(&state);
+        assert_eq!((mempool.ready_len(), mempool.future_len()), (2, 0));
+        assert_eq!(mempool.insert(&n1), Err(Rejection::Invalid(TxError::BadNonce { expected: 2, got: 1 })));
+        // and n2 as well
+        state.account_states.get_mut(&sender).unwrap().nonce = 2;
+        mempool.update_account_nonces(&state);
+        assert!(!mempool.exist(&n2.hash()));
+        assert_eq!(mempool.ready_len(), 1);
+    }
+
+    #[test]
+    fn executable_order() {
+        let mut mempool = Mempool::new();
+        let (a1, a2, b1, b2, c2) = (
+            generate_signed_transaction(0, 1, 10, 1),
+            generate_signed_transaction(0, 2, 10, 100),
+            generate_signed_transaction(1, 1, 10, 50),
+            generate_signed_transaction(1, 2, 10, 60),
+            generate_signed_transaction(2, 2, 10, 1000),
+        );
+        for tx in [&a2, &b2, &c2, &a1, &b1].iter() {
+            mempool.insert(tx).unwrap();
+        }
+        let order: Vec<H256> = mempool.executable().map(|tx| tx.hash()).collect();
+        // c2 is missing its first nonce, every sender's transactions stay in nonce order
+        assert_eq!(order, vec![b1.hash(), b2.hash(), a1.hash(), a2.hash()]);
+    }
+
+    #[test]
+    fn admission_against_tip_state() {
+        let mut mempool = Mempool::new();
+        let state = State::new(&ChainParams::default().premine);
+        let mut forged = generate_signed_transaction(0, 1, 10, 0);
+        forged.transaction.value += 1;
+        assert_eq!(mempool.admit(&forged, &state), Err(Rejection::Invalid(TxError::InvalidSignature)));
+        assert!(matches!(mempool.admit(&generate_signed_transaction(9, 1, 10, 0), &state), Err(Rejection::Invalid(TxError::UnknownSender(_)))));
+        assert_eq!(
+            mempool.admit(&generate_signed_transaction(0, 0, 10, 0), &state),
+            Err(Rejection::Invalid(TxError::BadNonce { expected: 1, got: 0 }))
+        );
+        assert!(matches!(
+            mempool.admit(&generate_signed_transaction(0, 1, 10, u32::MAX), &state),
+            Err(Rejection::Invalid(TxError::InsufficientBalance { .. }))
+        ));
+        assert_eq!(mempool.admit(&SignedTransaction::coinbase(Address::random(), 50, 1), &state), Err(Rejection::Coinbase));
+        // a future nonce is fine
+        assert_eq!(mempool.admit(&generate_signed_transaction(0, 3, 10, 0), &state), Ok(()));
+        assert_eq!(mempool.len(), 1);
+    }
+
+    #[test]
+    fn eviction_when_full() {
+        let config = MempoolConfig { max_transactions: 3, ..MempoolConfig::default() };
+        let mut mempool = Mempool::with_config(config);
+        let (a1, a2, b1, c1) = (
+            generate_signed_transaction(0, 1, 10, 50),
+            generate_signed_transaction(0, 2, 10, 5),
+            generate_signed_transaction(1, 1, 10, 10),
+            generate_signed_transaction(2, 1, 10, 20),
+        );
+        mempool.insert_at(&a1, 0).unwrap();
+        mempool.insert_at(&a2, 1).unwrap();
+        mempool.insert_at(&b1, 2).unwrap();
+        // a2 pays the least, and is the last of its sender
+        assert_eq!(mempool.insert_at(&c1, 3), Ok(()));
+        assert!(!mempool.exist(&a2.hash()));
+        // cheaper than everything in the pool
+        assert_eq!(mempool.insert_at(&generate_signed_transaction(0, 2, 10, 1), 4), Err(Rejection::PoolFull));
+        assert_eq!(mempool.len(), 3);
+        assert_eq!(mempool.stats().evicted, 1);
+
+        // without fees, the oldest goes first
+        let mut mempool = Mempool::with_config(MempoolConfig { max_transactions: 2, ..MempoolConfig::default() });
+        let (a1, b1, c1) = (
+            generate_signed_transaction(0, 1, 10, 0),
+            generate_signed_transaction(1, 1, 10, 0),
+            generate_signed_transaction(2, 1, 10, 0),
+        );
+        mempool.insert_at(&b1, 10).unwrap();
+        mempool.insert_at(&a1, 20).unwrap();
+        mempool.insert_at(&c1, 30).unwrap();
+        assert!(!mempool.exist(&b1.hash()));
+    }
+
+    #[test]
+    fn byte_limit() {
+        let size = bincode::serialized_size(&generate_signed_transaction(0, 1, 10, 0)).unwrap() as usize;
+        let mut mempool = Mempool::with_config(MempoolConfig { max_bytes: 2 * size, ..MempoolConfig::default() });
+        mempool.insert(&generate_signed_transaction(0, 1, 10, 1)).unwrap();
+        mempool.insert(&generate_signed_transaction(1, 1, 10, 2)).unwrap();
+        assert_eq!(mempool.bytes(), 2 * size);
+        mempool.insert(&generate_signed_transaction(2, 1, 10, 3)).unwrap();
+        assert_eq!((mempool.len(), mempool.bytes()), (2, 2 * size));
+    }
+
+    #[test]
+    fn expiry() {
+        let mut mempool = Mempool::with_config(MempoolConfig { ttl: 1000, ..MempoolConfig::default() });
+        let (old, new) = (generate_signed_transaction(0, 1, 10, 0), generate_signed_transaction(1, 1, 10, 0));
+        mempool.insert_at(&old, 0).unwrap();
+        mempool.insert_at(&new, 500).unwrap();
+        mempool.expire_at(1200);
+        assert!(!mempool.exist(&old.hash()));
+        assert!(mempool.exist(&new.hash()));
+        assert_eq!(mempool.stats().expired, 1);
+    }
+
+    #[test]
+    fn replace_by_fee() {
+        let mut mempool = Mempool::new();
+        let (n1, n2, n3) = (
+            generate_signed_transaction(0, 1, 10, 100),
+            generate_signed_transaction(0, 2, 10, 100),
+            generate_signed_transaction(0, 3, 10, 100),
+        );
+        mempool.insert(&n1).unwrap();
+        mempool.insert(&n2).unwrap();
+        mempool.insert(&n3).unwrap();
+        // a 10% bump is required
+        assert_eq!(mempool.insert(&generate_signed_transaction(0, 2, 10, 109)), Err(Rejection::ReplacementUnderpriced { required: 110, got: 109 }));
+        assert_eq!(mempool.insert(&generate_signed_transaction(0, 2, 10, 100)), Err(Rejection::ReplacementUnderpriced { required: 110, got: 100 }));
+        let replacement = generate_signed_transaction(0, 2, 10, 110);
+        assert_eq!(mempool.insert(&replacement), Ok(()));
+        assert!(!mempool.exist(&n2.hash()));
+        assert_eq!(mempool.stats().replaced, 1);
+        // the queue keeps its shape
+        assert_eq!((mempool.len(), mempool.ready_len()), (3, 3));
+        let order: Vec<H256> = mempool.executable().map(|tx| tx.hash()).collect();
+        assert_eq!(order, vec![n1.hash(), replacement.hash(), n3.hash()]);
+        assert_eq!(mempool.bytes(), 3 * bincode::serialized_size(&n1).unwrap() as usize);
+
+        // future transactions can be replaced too, and a zero fee needs a bump of one
+        let future = generate_signed_transaction(1, 5, 10, 0);
+        mempool.insert(&future).unwrap();
+        assert_eq!(mempool.insert(&generate_signed_transaction(1, 5, 10, 0)), Err(Rejection::ReplacementUnderpriced { required: 1, got: 0 }));
+        assert_eq!(mempool.insert(&generate_signed_transaction(1, 5, 10, 1)), Ok(()));
+        assert_eq!(mempool.future_len(), 1);
+    }
+
+    #[test]
+    fn revalidate_against_new_tip() {
+        let mut mempool = Mempool::new();
+        let mut state = State::new(&ChainParams::default().premine);
+        let (a1, a2, a3, b1, b5) = (
+            generate_signed_transaction(0, 1, 10, 0),
+            generate_signed_transaction(0, 2, 10, 0),
+            generate_signed_transaction(0, 3, 10, 0),
+            generate_signed_transaction(1, 1, 10, 0),
+            generate_signed_transaction(1, 5, 10, 0),
+        );
+        let c1 = generate_signed_transaction(7, 1, 10, 0);
+        for tx in [&a1, &a2, &a3, &b1, &b5, &c1].iter() {
+            mempool.insert(tx).unwrap();
+        }
+        // a can only pay for two transactions of 10, b for none
+        let (a, b) = (Address::from_public_key_bytes(&a1.public_key), Address::from_public_key_bytes(&b1.public_key));
+        state.account_states.get_mut(&a).unwrap().balance = 25;
+        state.account_states.get_mut(&b).unwrap().balance = 5;
+        mempool.revalidate(&state);
+        assert!(mempool.exist(&a1.hash()) && mempool.exist(&a2.hash()));
+        assert!(!mempool.exist(&a3.hash()));
+        assert!(!mempool.exist(&b1.hash()) && !mempool.exist(&b5.hash()));
+        // unknown sender
+        assert!(!mempool.exist(&c1.hash()));
+        assert_eq!(mempool.stats().invalidated, 4);
+    }
+
+    #[test]
+    fn save_and_restore() {
+        let dir = TempDir::new("mempool-test");
+        let path = dir.path().join("mempool.dat");
+        let mut mempool = Mempool::new();
+        let (a1, a2, b1) = (
+            generate_signed_transaction(0, 1, 10, 3),
+            generate_signed_transaction(0, 2, 10, 1),
+            generate_signed_transaction(1, 1, 10, 2),
+        );
+        mempool.insert_at(&a1, 0).unwrap();
+        mempool.insert_at(&a2, now()).unwrap();
+        mempool.insert(&b1).unwrap();
+        mempool.save(&path).unwrap();
+
+        // meanwhile b1 got mined
+        let mut state = State::new(&ChainParams::default().premine);
+        state.account_states.get_mut(&Address::from_public_key_bytes(&b1.public_key)).unwrap().nonce = 1;
+        let mut restored = Mempool::new();
+        assert_eq!(restored.restore(&path, &state).unwrap(), 1);
+        // a1 kept its arrival time and expired, which leaves a2 waiting for nonce 1
+        assert!(!restored.exist(&a1.hash()));
+        assert!(restored.exist(&a2.hash()));
+        assert_eq!(restored.future_len(), 1);
+    }
 }
@@ -0,0 +1,241 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+use hex_literal::hex;
+use ring::digest;
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use serde::{Serialize, Deserialize, Serializer, Deserializer};
+use serde::de::Error;
+use super::address::Address;
+use super::hash::{H256, Hashable};
+
+/// How the PoW target of a new block is derived from its ancestors.
+/// Block times are in milliseconds, like `Header::timestamp`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RetargetRule {
+    /// Every block copies its parent's target
+    Fixed,
+    /// Bitcoin style: every `interval` blocks, scale the target by how much faster or slower
+    /// than `block_time` the last `interval` blocks were produced
+    Interval { interval: usize, block_time: u64 },
+    /// Every block gets the average target of the last `window` blocks, scaled by how much faster
+    /// or slower than `block_time` they were produced
+    MovingAverage { window: usize, block_time: u64 },
+}
+
+impl FromStr for RetargetRule {
+    type Err = String;
+
+    /// Parses `fixed`, `interval:<blocks>:<block time ms>` or `average:<blocks>:<block time ms>`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split(':').collect();
+        let numbers = || -> Result<(usize, u64), String> {
+            if parts.len() != 3 {
+                return Err(format!("expected {}:<blocks>:<block time ms>", parts[0]));
+            }
+            let blocks = parts[1].parse::<usize>().map_err(|e| e.to_string())?;
+            let block_time = parts[2].parse::<u64>().map_err(|e| e.to_string())?;
+            if blocks == 0 || block_time == 0 {
+                return Err("blocks and block time must be positive".to_string());
+            }
+            // a retarget scales by up to four times the time the blocks should take
+            if blocks as u128 * block_time as u128 * 4 > u64::MAX as u128 {
+                return Err("blocks times block time is too large".to_string());
+            }
+            Ok((blocks, block_time))
+        };
+        match parts[0] {
+            "fixed" if parts.len() == 1 => Ok(RetargetRule::Fixed),
+            "interval" => numbers().map(|(interval, block_time)| RetargetRule::Interval { interval, block_time }),
+            "average" => numbers().map(|(window, block_time)| RetargetRule::MovingAverage { window, block_time }),
+            _ => Err(format!("unknown retarget rule {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for RetargetRule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RetargetRule::Fixed => write!(f, "fixed"),
+            RetargetRule::Interval { interval, block_time } => write!(f, "interval:{}:{}", interval, block_time),
+            RetargetRule::MovingAverage { window, block_time } => write!(f, "average:{}:{}", window, block_time),
+        }
+    }
+}
+
+// in genesis files, rules are written the same way as on the command line
+impl Serialize for RetargetRule {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for RetargetRule {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?.parse().map_err(D::Error::custom)
+    }
+}
+
+/// Hex strings instead of byte arrays for hashes and addresses in genesis files
+mod hex_bytes {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use serde::de::Error;
+    use std::convert::TryFrom;
+
+    pub fn serialize<T: AsRef<[u8]>, S: Serializer>(value: &T, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&hex::encode(value))
+    }
+
+    pub fn deserialize<'de, T, D, const N: usize>(deserializer: D) -> Result<T, D::Error>
+    where T: From<[u8; N]>, D: Deserializer<'de> {
+        let bytes = hex::decode(String::deserialize(deserializer)?).map_err(D::Error::custom)?;
+        let bytes = <[u8; N]>::try_from(bytes.as_slice()).map_err(|_| D::Error::custom(format!("expected {} bytes", N)))?;
+        Ok(T::from(bytes))
+    }
+}
+
+/// Coins credited to an account in the genesis state
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Allocation {
+    #[serde(with = "hex_bytes")]
+    pub address: Address,
+    pub balance: u32,
+}
+
+/// Consensus parameters shared by every node of a network.
+/// Loaded from a JSON genesis file, where any missing field takes its default value.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ChainParams {
+    /// Timestamp (ms) of the genesis block
+    pub genesis_timestamp: u128,
+    /// Target of the genesis block
+    #[serde(with = "hex_bytes")]
+    pub initial_target: H256,
+    /// Easiest target a retarget may produce
+    #[serde(with = "hex_bytes")]
+    pub max_target: H256,
+    pub retarget: RetargetRule,
+    /// How far (ms) ahead of the local clock a block timestamp may be
+    pub max_future_drift: u64,
+    /// Largest allowed serialized block, in bytes
+    pub max_block_size: usize,
+    /// Largest allowed number of transactions in a block
+    pub max_block_transactions: usize,
+    /// Coins minted by the coinbase of the first blocks
+    pub block_reward: u32,
+    /// The reward halves every `halving_interval` blocks, 0 keeps it constant
+    pub halving_interval: usize,
+    /// Accounts funded in the genesis state
+    pub premine: Vec<Allocation>,
+}
+
+impl ChainParams {
+    /// Read the parameters from a JSON genesis file
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let params = serde_json::from_str(&fs::read_to_string(path)?)?;
+        Ok(params)
+    }
+
+    /// Coins minted by the coinbase of the block at `height`
+    pub fn block_reward_at(&self, height: usize) -> u32 {
+        if self.halving_interval == 0 {
+            return self.block_reward;
+        }
+        self.block_reward.checked_shr((height / self.halving_interval) as u32).unwrap_or(0)
+    }
+}
+
+// committed to by the genesis block, so that nodes with different parameters never share a chain
+impl Hashable for ChainParams {
+    fn hash(&self) -> H256 {
+        let encoded_params = bincode::serialize(&self).unwrap();
+        let digest = digest::digest(&digest::SHA256, &encoded_params);
+        digest.into()
+    }
+}
+
+impl Default for ChainParams {
+    fn default() -> Self {
+        let initial_target: H256 = hex!("000fffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff").into();
+        // the three deterministic accounts of the default testnet, one per node
+        let premine = (0..3u8).map(|seed| {
+            let key_pair = Ed25519KeyPair::from_seed_unchecked(&[seed; 32]).unwrap();
+            Allocation {
+                address: Address::from_public_key_bytes(key_pair.public_key().as_ref()),
+                balance: u32::MAX / 3,
+            }
+        }).collect();
+        ChainParams {
+            genesis_timestamp: 0,
+            initial_target,
+            max_target: initial_target,
+            retarget: RetargetRule::Fixed,
+            max_future_drift: 2 * 60 * 60 * 1000,
+            max_block_size: 1_000_000,
+            max_block_transactions: 50,
+            block_reward: 50,
+            halving_interval: 210_000,
+            premine,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn load_genesis_file() {
+        let json = r#"{
+            "genesis_timestamp": 1700000000000,
+            "initial_target": "00ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+            "retarget": "interval:10:5000",
+            "block_reward": 50,
+            "premine": [{"address": "1851a0eae0060a132cf0f64a0ffaea248de6cba0", "balance": 1000}]
+        }"#;
+        let dir = TempDir::new("genesis-test");
+        let path = dir.path().join("genesis.json");
+        fs::write(&path, json).unwrap();
+        let params = ChainParams::load(&path).unwrap();
+
+        assert_eq!(params.genesis_timestamp, 1700000000000);
+        assert_eq!(params.initial_target, hex!("00ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff").into());
+        assert_eq!(params.retarget, RetargetRule::Interval { interval: 10, block_time: 5000 });
+        assert_eq!(params.block_reward, 50);
+        assert_eq!(params.premine, vec![Allocation { address: hex!("1851a0eae0060a132cf0f64a0ffaea248de6cba0").into(), balance: 1000 }]);
+        // unspecified fields keep their defaults
+        assert_eq!(params.max_block_transactions, ChainParams::default().max_block_transactions);
+
+        // and a round trip gives back the same parameters
+        let reloaded: ChainParams = serde_json::from_str(&serde_json::to_string(&params).unwrap()).unwrap();
+        assert_eq!(reloaded.premine, params.premine);
+        assert_eq!(reloaded.max_target, params.max_target);
+    }
+
+    #[test]
+    fn parse_retarget_rules() {
+        assert_eq!("fixed".parse::<RetargetRule>(), Ok(RetargetRule::Fixed));
+        assert_eq!("average:3:1000".parse::<RetargetRule>(), Ok(RetargetRule::MovingAverage { window: 3, block_time: 1000 }));
+        assert!("interval:0:1000".parse::<RetargetRule>().is_err());
+        assert!("interval:10:0".parse::<RetargetRule>().is_err());
+        assert!(format!("interval:{}:{}", u32::MAX, u64::MAX / 2).parse::<RetargetRule>().is_err());
+        // the genesis file goes through the same checks
+        assert!(serde_json::from_str::<ChainParams>(r#"{"retarget": "interval:0:1000"}"#).is_err());
+    }
+
+    #[test]
+    fn reward_halving() {
+        let params = ChainParams { block_reward: 50, halving_interval: 10, ..ChainParams::default() };
+        assert_eq!(params.block_reward_at(1), 50);
+        assert_eq!(params.block_reward_at(9), 50);
+        assert_eq!(params.block_reward_at(10), 25);
+        assert_eq!(params.block_reward_at(25), 12);
+        assert_eq!(params.block_reward_at(10 * 40), 0);
+        let constant = ChainParams { halving_interval: 0, ..params };
+        assert_eq!(constant.block_reward_at(1000), 50);
+    }
+}
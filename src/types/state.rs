@@ -1,11 +1,11 @@
 use std::collections::HashMap;
 use rand::Rng;
-use ring::signature::{Ed25519KeyPair, KeyPair};
 use super::{
     address::Address,
 };
 use super::transaction::{Transaction, SignedTransaction, verify, sign};
 use super::hash::H256;
+use super::params::{Allocation, ChainParams};
 use super::block::Block;
 use crate::types::hash::Hashable;
 
@@ -22,23 +22,15 @@ pub struct State {
 }
 
 impl State {
-    pub fn new() -> Self {
+    /// Genesis state, funding the accounts of the premine
+    pub fn new(premine: &[Allocation]) -> Self {
         let mut states = HashMap::new();
-        // generate three deterministic accounts accorss 3 nodes
-
-        let seed = vec![0, 1, 2];
-
-        // ICO
-        for i in 0..3 {
-            let key_pair = Ed25519KeyPair::from_seed_unchecked(&[seed[i];32]).unwrap();
-            let pub_key = key_pair.public_key();
-            let address = Address::from_public_key_bytes(pub_key.as_ref());
-            let balance = u32::MAX / 3;
+        for allocation in premine {
             let account_state = AccountState {
                 nonce: 0,
-                balance: balance,
+                balance: allocation.balance,
             };
-            states.insert(address, account_state);
+            states.insert(allocation.address, account_state);
         }
         Self {
             account_states: states,
@@ -66,27 +58,36 @@ impl State {
         self.account_states.insert(address, account_state);
     }
 
+    /// Credit minted coins (e.g. a block reward), creating the account if needed. Returns the new
+    /// balance, or `None` without crediting anything if it would overflow.
+    pub fn credit(&mut self, address: Address, value: u32) -> Option<u32> {
+        let account_state = self.account_states.entry(address).or_insert(AccountState { nonce: 0, balance: 0 });
+        account_state.balance = account_state.balance.checked_add(value)?;
+        Some(account_state.balance)
+    }
+
     // make sure that the transaction is valid before calling this function
     // also make sure that the sender and receiver exist
     pub fn update_with_tx(&mut self, signed_tx: &SignedTransaction) {
         let tx = signed_tx.transaction.clone();
         let receiver = tx.receiver.clone();
         let value = tx.value;
+        let cost = signed_tx.cost();
         let nonce = tx.account_nonce;
         let sender = Address::from_public_key_bytes(&signed_tx.public_key);
     
         assert!( self.exist(&sender) && self.exist(&receiver) );
-        assert!( self.get_balance(&sender) >= value );
+        assert!( self.get_balance(&sender) as u64 >= cost );
         assert!( self.get_nonce(&sender)+1 == nonce );
 
+        // the sender may pay itself, so the receiver is only read once the sender is updated
         let mut sender_state = self.account_states.get(&sender).unwrap().clone();
-        let mut receiver_state = self.account_states.get(&receiver).unwrap().clone();
         sender_state.nonce += 1;
-        sender_state.balance -= value;
-        receiver_state.balance += value;
-
-        // reinsert the updated states since rust does not support mutable reference to a value in a hashmap
+        sender_state.balance -= cost as u32;
         self.account_states.insert(sender, sender_state);
+
+        let mut receiver_state = self.account_states.get(&receiver).unwrap().clone();
+        receiver_state.balance += value;
         self.account_states.insert(receiver, receiver_state);
     }
 
@@ -122,8 +123,8 @@ pub struct StatePerBlock{
 }
 
 impl StatePerBlock {
-    pub fn new(genesis_hash: &H256) -> Self {
-        let state = State::new(); // three initial accounts initialized
+    pub fn new(genesis_hash: &H256, params: &ChainParams) -> Self {
+        let state = State::new(&params.premine);
         let mut s_copy = HashMap::new();
         s_copy.insert(genesis_hash.clone(), state);
         Self {
@@ -151,6 +152,11 @@ impl StatePerBlock {
 
         for tx in block.get_transactions() {
             let signed_tx = tx.clone();
+            if signed_tx.is_coinbase() {
+                let credited = state.credit(signed_tx.transaction.receiver, signed_tx.transaction.value);
+                assert!(credited.is_some());
+                continue;
+            }
 
             let sender = Address::from_public_key_bytes(&signed_tx.public_key);
             let receiver = signed_tx.transaction.receiver.clone();
@@ -8,6 +8,8 @@ use super::address::Address;
 pub struct Transaction {
     pub receiver: Address,
     pub value: u32,
+    /// Paid by the sender on top of `value`, collected by the miner of the block
+    pub fee: u32,
     pub account_nonce: u32,
 }
 
@@ -18,6 +20,27 @@ pub struct SignedTransaction {
     pub public_key: Vec<u8>,
 }
 
+impl SignedTransaction {
+    /// Coinbase of the block at `height`, minting `value` coins for the miner.
+    /// It carries no signature; the height, stored as the nonce, makes every coinbase unique.
+    pub fn coinbase(miner: Address, value: u32, height: u32) -> Self {
+        SignedTransaction {
+            transaction: Transaction { receiver: miner, value, fee: 0, account_nonce: height },
+            signature: Vec::new(),
+            public_key: Vec::new(),
+        }
+    }
+
+    pub fn is_coinbase(&self) -> bool {
+        self.signature.is_empty() && self.public_key.is_empty()
+    }
+
+    /// Total amount debited from the sender
+    pub fn cost(&self) -> u64 {
+        self.transaction.value as u64 + self.transaction.fee as u64
+    }
+}
+
 /// Create digital signature of a transaction
 pub fn sign(t: &Transaction, key: &Ed25519KeyPair) -> Signature {
     let encoded_trans = bincode::serialize(&t).unwrap();
@@ -34,20 +57,32 @@ pub fn verify(t: &Transaction, public_key: &[u8], signature: &[u8]) -> bool {
 #[cfg(any(test, test_utilities))]
 pub fn generate_random_transaction() -> Transaction {
     let mut rng = rand::thread_rng();
-    let mut sender = [0u8; 20];
     let mut receiver = [0u8; 20];
     let value : u32 = rng.gen::<u32>();
-    rng.fill(&mut sender);
     rng.fill(&mut receiver);
 
     Transaction{
-        sender: Address::from(sender),
         receiver: Address::from(receiver),
-        value: value, // Random value between 1 and 1000
+        value: value,
+        fee: rng.gen::<u32>(),
+        account_nonce: rng.gen::<u32>(),
     }
     
 }
 
+/// A transaction to a random receiver, signed with the key generated from `[seed; 32]`
+#[cfg(any(test, test_utilities))]
+pub fn generate_signed_transaction(seed: u8, nonce: u32, value: u32, fee: u32) -> SignedTransaction {
+    use ring::signature::KeyPair;
+    let key = Ed25519KeyPair::from_seed_unchecked(&[seed; 32]).unwrap();
+    let transaction = Transaction { receiver: Address::random(), value, fee, account_nonce: nonce };
+    SignedTransaction {
+        signature: sign(&transaction, &key).as_ref().to_vec(),
+        public_key: key.public_key().as_ref().to_vec(),
+        transaction,
+    }
+}
+
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
 
 #[cfg(test)]
@@ -0,0 +1,507 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::blockchain::Blockchain;
+use crate::types::address::Address;
+use crate::types::block::Block;
+use crate::types::hash::{H256, Hashable};
+use crate::types::merkle::MerkleTree;
+use crate::types::state::{State, StatePerBlock};
+use crate::types::transaction::{SignedTransaction, verify};
+
+/// Why a transaction cannot be applied on top of a state
+#[derive(Debug, Clone, PartialEq)]
+pub enum TxError {
+    InvalidSignature,
+    UnknownSender(Address),
+    BadNonce { expected: u32, got: u32 },
+    InsufficientBalance { balance: u32, cost: u64 },
+    /// The receiver's balance cannot hold the transferred value
+    ReceiverOverflow { balance: u32, value: u32 },
+}
+
+impl fmt::Display for TxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TxError::InvalidSignature => write!(f, "invalid signature"),
+            TxError::UnknownSender(sender) => write!(f, "unknown sender {}", sender),
+            TxError::BadNonce { expected, got } => write!(f, "expected nonce {}, got {}", expected, got),
+            TxError::InsufficientBalance { balance, cost } => write!(f, "balance {} cannot cover {}", balance, cost),
+            TxError::ReceiverOverflow { balance, value } => write!(f, "receiver balance {} cannot take {} more", balance, value),
+        }
+    }
+}
+
+/// Why a block is rejected
+#[derive(Debug, Clone, PartialEq)]
+pub enum BlockError {
+    /// The parent is not in the blockchain (yet), the block may be an orphan
+    UnknownParent(H256),
+    /// The block hash does not meet its own target
+    InsufficientWork,
+    TooManyTransactions { max: usize, got: usize },
+    /// The serialized block exceeds the size limit
+    TooLarge { max: usize, got: usize },
+    /// The target does not follow the retarget rule
+    WrongDifficulty { expected: H256, got: H256 },
+    /// The header does not commit to the transactions in the content
+    MerkleRootMismatch,
+    /// The timestamp is not after the median time of the last `MEDIAN_TIME_SPAN` blocks
+    TimestampTooOld { median_time_past: u128, got: u128 },
+    /// The timestamp is further ahead of the local clock than the allowed drift
+    TimestampTooNew { max: u128, got: u128 },
+    DuplicateTransaction(H256),
+    /// A coinbase anywhere but in the first slot
+    MisplacedCoinbase(H256),
+    /// The coinbase does not claim exactly the block reward plus the fees
+    WrongReward { expected: u64, got: u32 },
+    /// The coinbase nonce is not the block height
+    WrongCoinbaseHeight { expected: u32, got: u32 },
+    /// The coinbase pays a fee, which only transfers can do
+    CoinbaseFee(u32),
+    InvalidTransaction(H256, TxError),
+}
+
+impl fmt::Display for BlockError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BlockError::UnknownParent(parent) => write!(f, "unknown parent {}", parent),
+            BlockError::InsufficientWork => write!(f, "hash above target"),
+            BlockError::TooManyTransactions { max, got } => write!(f, "{} transactions, at most {} allowed", got, max),
+            BlockError::TooLarge { max, got } => write!(f, "{} bytes, at most {} allowed", got, max),
+            BlockError::WrongDifficulty { expected, got } => write!(f, "expected target {}, got {}", expected, got),
+            BlockError::MerkleRootMismatch => write!(f, "merkle root does not match the transactions"),
+            BlockError::TimestampTooOld { median_time_past, got } => {
+                write!(f, "timestamp {} not after median time past {}", got, median_time_past)
+            }
+            BlockError::TimestampTooNew { max, got } => write!(f, "timestamp {} later than {}", got, max),
+            BlockError::DuplicateTransaction(hash) => write!(f, "duplicate transaction {}", hash),
+            BlockError::MisplacedCoinbase(hash) => write!(f, "coinbase {} not in the first slot", hash),
+            BlockError::WrongReward { expected, got } => write!(f, "coinbase mints {}, expected {}", got, expected),
+            BlockError::WrongCoinbaseHeight { expected, got } => write!(f, "coinbase height {}, expected {}", got, expected),
+            BlockError::CoinbaseFee(fee) => write!(f, "coinbase pays a fee of {}", fee),
+            BlockError::InvalidTransaction(hash, e) => write!(f, "invalid transaction {}: {}", hash, e),
+        }
+    }
+}
+
+/// Check that `tx` can be applied on top of `state`
+pub fn validate_transaction(tx: &SignedTransaction, state: &State) -> Result<(), TxError> {
+    if !verify(&tx.transaction, &tx.public_key, &tx.signature) {
+        return Err(TxError::InvalidSignature);
+    }
+    let sender = Address::from_public_key_bytes(&tx.public_key);
+    if !state.exist(&sender) {
+        return Err(TxError::UnknownSender(sender));
+    }
+    let expected = state.get_nonce(&sender) + 1;
+    if tx.transaction.account_nonce != expected {
+        return Err(TxError::BadNonce { expected, got: tx.transaction.account_nonce });
+    }
+    let balance = state.get_balance(&sender);
+    if (balance as u64) < tx.cost() {
+        return Err(TxError::InsufficientBalance { balance, cost: tx.cost() });
+    }
+    // the sender may pay itself, in which case it receives after paying
+    let receiver = tx.transaction.receiver;
+    let receiver_balance = if receiver == sender {
+        balance - tx.cost() as u32
+    } else if state.exist(&receiver) {
+        state.get_balance(&receiver)
+    } else {
+        0
+    };
+    if receiver_balance.checked_add(tx.transaction.value).is_none() {
+        return Err(TxError::ReceiverOverflow { balance: receiver_balance, value: tx.transaction.value });
+    }
+    Ok(())
+}
+
+/// Check that `tx` can wait in the mempool on top of `state`: like `validate_transaction`, except
+/// that the nonce may be any unused one, since earlier transactions of the sender may still be pending
+pub fn validate_pending_transaction(tx: &SignedTransaction, state: &State) -> Result<(), TxError> {
+    if !verify(&tx.transaction, &tx.public_key, &tx.signature) {
+        return Err(TxError::InvalidSignature);
+    }
+    let sender = Address::from_public_key_bytes(&tx.public_key);
+    if !state.exist(&sender) {
+        return Err(TxError::UnknownSender(sender));
+    }
+    let expected = state.get_nonce(&sender) + 1;
+    if tx.transaction.account_nonce < expected {
+        return Err(TxError::BadNonce { expected, got: tx.transaction.account_nonce });
+    }
+    let balance = state.get_balance(&sender);
+    if (balance as u64) < tx.cost() {
+        return Err(TxError::InsufficientBalance { balance, cost: tx.cost() });
+    }
+    Ok(())
+}
+
+/// Run every consensus check on a block that is about to be inserted on top of its parent.
+/// The transactions are executed in order, so later ones see the effects of earlier ones.
+pub fn validate_block(block: &Block, blockchain: &Blockchain, state_per_block: &StatePerBlock) -> Result<(), BlockError> {
+    // cheapest check first, so that junk is dropped before any lookup
+    if block.hash() > block.get_difficulty() {
+        return Err(BlockError::InsufficientWork);
+    }
+
+    let params = blockchain.params();
+    if block.content.transactions.len() > params.max_block_transactions {
+        return Err(BlockError::TooManyTransactions { max: params.max_block_transactions, got: block.content.transactions.len() });
+    }
+    if block.size() > params.max_block_size {
+        return Err(BlockError::TooLarge { max: params.max_block_size, got: block.size() });
+    }
+
+    let parent_hash = block.get_parent();
+    if !blockchain.exist(&parent_hash) || !state_per_block.exist(&parent_hash) {
+        return Err(BlockError::UnknownParent(parent_hash));
+    }
+
+    let expected = blockchain.next_difficulty(&parent_hash).unwrap();
+    if block.get_difficulty() != expected {
+        return Err(BlockError::WrongDifficulty { expected, got: block.get_difficulty() });
+    }
+
+    let median_time_past = blockchain.median_time_past(&parent_hash).unwrap();
+    if block.header.timestamp <= median_time_past {
+        return Err(BlockError::TimestampTooOld { median_time_past, got: block.header.timestamp });
+    }
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
+    let max = now + params.max_future_drift as u128;
+    if block.header.timestamp > max {
+        return Err(BlockError::TimestampTooNew { max, got: block.header.timestamp });
+    }
+
+    let transactions = &block.content.transactions;
+    if MerkleTree::new(transactions.as_slice()).root() != block.header.merkle_root {
+        return Err(BlockError::MerkleRootMismatch);
+    }
+
+    let mut seen = HashSet::new();
+    for tx in transactions.iter() {
+        if !seen.insert(tx.hash()) {
+            return Err(BlockError::DuplicateTransaction(tx.hash()));
+        }
+    }
+
+    // the coinbase is optional, a miner may give up the reward and fees
+    let height = blockchain.get_height(&parent_hash).unwrap() + 1;
+    let fees: u64 = transactions.iter().filter(|tx| !tx.is_coinbase()).map(|tx| tx.transaction.fee as u64).sum();
+    for (i, tx) in transactions.iter().enumerate() {
+        if !tx.is_coinbase() {
+            continue;
+        }
+        if i != 0 {
+            return Err(BlockError::MisplacedCoinbase(tx.hash()));
+        }
+        if tx.transaction.fee != 0 {
+            return Err(BlockError::CoinbaseFee(tx.transaction.fee));
+        }
+        let expected = params.block_reward_at(height) as u64 + fees;
+        if tx.transaction.value as u64 != expected {
+            return Err(BlockError::WrongReward { expected, got: tx.transaction.value });
+        }
+        if tx.transaction.account_nonce != height as u32 {
+            return Err(BlockError::WrongCoinbaseHeight { expected: height as u32, got: tx.transaction.account_nonce });
+        }
+    }
+
+    let mut state = state_per_block.get_state(&parent_hash);
+    for tx in transactions.iter() {
+        if tx.is_coinbase() {
+            let (receiver, value) = (tx.transaction.receiver, tx.transaction.value);
+            let balance = if state.exist(&receiver) { state.get_balance(&receiver) } else { 0 };
+            if state.credit(receiver, value).is_none() {
+                return Err(BlockError::InvalidTransaction(tx.hash(), TxError::ReceiverOverflow { balance, value }));
+            }
+            continue;
+        }
+        validate_transaction(tx, &state).map_err(|e| BlockError::InvalidTransaction(tx.hash(), e))?;
+        if !state.exist(&tx.transaction.receiver) {
+            state.add_account(tx.transaction.receiver, 0);
+        }
+        state.update_with_tx(tx);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::store::MemoryStore;
+    use crate::types::block::{Content, Header};
+    use crate::types::params::{Allocation, ChainParams};
+    use crate::types::transaction::generate_signed_transaction;
+
+    fn setup() -> (Blockchain, StatePerBlock) {
+        let params = ChainParams {
+            initial_target: hex!("7fffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff").into(),
+            max_block_transactions: 4,
+            max_block_size: 1000,
+            ..Default::default()
+        };
+        let blockchain = Blockchain::open(params, Box::new(MemoryStore)).unwrap();
+        let state_per_block = StatePerBlock::new(&blockchain.tip(), blockchain.params());
+        (blockchain, state_per_block)
+    }
+
+    /// Search nonces until the hash meets the target (or misses it, if `valid` is false)
+    fn mine(mut block: Block, valid: bool) -> Block {
+        while (block.hash() <= block.get_difficulty()) != valid {
+            block.header.nonce += 1;
+        }
+        block
+    }
+
+    /// An unmined block on top of the tip that passes every check
+    fn child(blockchain: &Blockchain, transactions: Vec<SignedTransaction>) -> Block {
+        let parent = blockchain.tip();
+        let header = Header {
+            parent,
+            nonce: 0,
+            difficulty: blockchain.next_difficulty(&parent).unwrap(),
+            timestamp: blockchain.get_block(&parent).unwrap().header.timestamp + 1,
+            merkle_root: MerkleTree::new(transactions.as_slice()).root(),
+        };
+        Block { header, content: Content::new(transactions) }
+    }
+
+    #[test]
+    fn valid_block() {
+        let (blockchain, state_per_block) = setup();
+        // the second transaction relies on the first one's nonce
+        let txs = vec![
+            generate_signed_transaction(0, 1, 10, 0),
+            generate_signed_transaction(0, 2, 10, 0),
+            generate_signed_transaction(1, 1, 10, 0),
+        ];
+        let block = mine(child(&blockchain, txs), true);
+        assert_eq!(validate_block(&block, &blockchain, &state_per_block), Ok(()));
+    }
+
+    #[test]
+    fn insufficient_work() {
+        let (blockchain, state_per_block) = setup();
+        let block = mine(child(&blockchain, vec![]), false);
+        assert_eq!(validate_block(&block, &blockchain, &state_per_block), Err(BlockError::InsufficientWork));
+    }
+
+    #[test]
+    fn unknown_parent() {
+        let (blockchain, state_per_block) = setup();
+        let mut block = child(&blockchain, vec![]);
+        block.header.parent = H256::default();
+        let block = mine(block, true);
+        assert_eq!(validate_block(&block, &blockchain, &state_per_block), Err(BlockError::UnknownParent(H256::default())));
+    }
+
+    #[test]
+    fn block_limits() {
+        let (blockchain, state_per_block) = setup();
+        let txs: Vec<SignedTransaction> = (1..=5).map(|nonce| generate_signed_transaction(0, nonce, 10, 0)).collect();
+        let block = mine(child(&blockchain, txs[..4].to_vec()), true);
+        assert!(block.size() < 1000);
+        assert_eq!(validate_block(&block, &blockchain, &state_per_block), Ok(()));
+        let block = mine(child(&blockchain, txs.clone()), true);
+        assert_eq!(
+            validate_block(&block, &blockchain, &state_per_block),
+            Err(BlockError::TooManyTransactions { max: 4, got: 5 })
+        );
+
+        let mut bloated = txs[0].clone();
+        bloated.public_key.extend_from_slice(&[0; 1000]);
+        let block = mine(child(&blockchain, vec![bloated]), true);
+        assert!(matches!(validate_block(&block, &blockchain, &state_per_block), Err(BlockError::TooLarge { max: 1000, .. })));
+    }
+
+    #[test]
+    fn wrong_difficulty() {
+        let (blockchain, state_per_block) = setup();
+        let mut block = child(&blockchain, vec![]);
+        block.header.difficulty = H256::MAX;
+        let block = mine(block, true);
+        assert!(matches!(validate_block(&block, &blockchain, &state_per_block), Err(BlockError::WrongDifficulty { .. })));
+    }
+
+    #[test]
+    fn timestamp_not_after_median_time_past() {
+        let (mut blockchain, mut state_per_block) = setup();
+        // ten blocks 1000ms apart, the median of the last eleven timestamps is 5000
+        for _ in 0..10 {
+            let mut block = child(&blockchain, vec![]);
+            block.header.timestamp += 999;
+            let block = mine(block, true);
+            blockchain.insert(&block).unwrap();
+            state_per_block.update_with_block(&block);
+        }
+        // older than the parent but newer than the median is fine
+        let mut block = child(&blockchain, vec![]);
+        block.header.timestamp = 5001;
+        assert_eq!(validate_block(&mine(block.clone(), true), &blockchain, &state_per_block), Ok(()));
+        block.header.timestamp = 5000;
+        assert_eq!(
+            validate_block(&mine(block, true), &blockchain, &state_per_block),
+            Err(BlockError::TimestampTooOld { median_time_past: 5000, got: 5000 })
+        );
+    }
+
+    #[test]
+    fn timestamp_too_far_in_future() {
+        let (blockchain, state_per_block) = setup();
+        let drift = blockchain.params().max_future_drift as u128;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
+        let mut block = child(&blockchain, vec![]);
+        block.header.timestamp = now + drift / 2;
+        assert_eq!(validate_block(&mine(block.clone(), true), &blockchain, &state_per_block), Ok(()));
+        block.header.timestamp = now + drift + 60 * 1000;
+        assert!(matches!(
+            validate_block(&mine(block, true), &blockchain, &state_per_block),
+            Err(BlockError::TimestampTooNew { .. })
+        ));
+    }
+
+    #[test]
+    fn merkle_root_mismatch() {
+        let (blockchain, state_per_block) = setup();
+        let mut block = child(&blockchain, vec![generate_signed_transaction(0, 1, 10, 0)]);
+        block.content.transactions.push(generate_signed_transaction(1, 1, 10, 0));
+        let block = mine(block, true);
+        assert_eq!(validate_block(&block, &blockchain, &state_per_block), Err(BlockError::MerkleRootMismatch));
+    }
+
+    #[test]
+    fn duplicate_transaction() {
+        let (blockchain, state_per_block) = setup();
+        let tx = generate_signed_transaction(0, 1, 10, 0);
+        let block = mine(child(&blockchain, vec![tx.clone(), tx.clone()]), true);
+        assert_eq!(validate_block(&block, &blockchain, &state_per_block), Err(BlockError::DuplicateTransaction(tx.hash())));
+    }
+
+    #[test]
+    fn coinbase() {
+        let (blockchain, state_per_block) = setup();
+        let miner = Address::random();
+        let reward = blockchain.params().block_reward_at(1);
+        let block = mine(child(&blockchain, vec![SignedTransaction::coinbase(miner, reward, 1), generate_signed_transaction(0, 1, 10, 0)]), true);
+        assert_eq!(validate_block(&block, &blockchain, &state_per_block), Ok(()));
+        let mut rewarded = state_per_block.clone();
+        rewarded.update_with_block(&block);
+        assert_eq!(rewarded.get_state(&block.hash()).get_balance(&miner), reward);
+
+        let greedy = SignedTransaction::coinbase(miner, reward + 1, 1);
+        let block = mine(child(&blockchain, vec![greedy]), true);
+        assert_eq!(
+            validate_block(&block, &blockchain, &state_per_block),
+            Err(BlockError::WrongReward { expected: reward as u64, got: reward + 1 })
+        );
+
+        let replayed = SignedTransaction::coinbase(miner, reward, 7);
+        let block = mine(child(&blockchain, vec![replayed]), true);
+        assert_eq!(
+            validate_block(&block, &blockchain, &state_per_block),
+            Err(BlockError::WrongCoinbaseHeight { expected: 1, got: 7 })
+        );
+
+        let late = SignedTransaction::coinbase(miner, reward, 1);
+        let block = mine(child(&blockchain, vec![generate_signed_transaction(0, 1, 10, 0), late.clone()]), true);
+        assert_eq!(validate_block(&block, &blockchain, &state_per_block), Err(BlockError::MisplacedCoinbase(late.hash())));
+    }
+
+    #[test]
+    fn coinbase_overflow() {
+        let (blockchain, mut state_per_block) = setup();
+        let reward = blockchain.params().block_reward_at(1);
+        // a receiver this close to the limit cannot take the reward, it must not be silently burnt
+        let rich = Address::random();
+        state_per_block.state_copy.get_mut(&blockchain.tip()).unwrap().add_account(rich, u32::MAX - 1);
+        let coinbase = SignedTransaction::coinbase(rich, reward, 1);
+        let block = mine(child(&blockchain, vec![coinbase.clone()]), true);
+        assert_eq!(
+            validate_block(&block, &blockchain, &state_per_block),
+            Err(BlockError::InvalidTransaction(coinbase.hash(), TxError::ReceiverOverflow { balance: u32::MAX - 1, value: reward }))
+        );
+    }
+
+    #[test]
+    fn fees() {
+        let (blockchain, state_per_block) = setup();
+        let (miner, reward) = (Address::random(), blockchain.params().block_reward_at(1));
+        let (tx1, tx2) = (generate_signed_transaction(0, 1, 10, 3), generate_signed_transaction(1, 1, 10, 4));
+        let sender = Address::from_public_key_bytes(&tx1.public_key);
+
+        let block = mine(child(&blockchain, vec![SignedTransaction::coinbase(miner, reward, 1), tx1.clone(), tx2.clone()]), true);
+        assert_eq!(
+            validate_block(&block, &blockchain, &state_per_block),
+            Err(BlockError::WrongReward { expected: reward as u64 + 7, got: reward })
+        );
+
+        let block = mine(child(&blockchain, vec![SignedTransaction::coinbase(miner, reward + 7, 1), tx1, tx2]), true);
+        assert_eq!(validate_block(&block, &blockchain, &state_per_block), Ok(()));
+        let mut after = state_per_block.clone();
+        after.update_with_block(&block);
+        let state = after.get_state(&block.hash());
+        assert_eq!(state.get_balance(&miner), reward + 7);
+        assert_eq!(state.get_balance(&sender), state_per_block.get_state(&blockchain.tip()).get_balance(&sender) - 13);
+    }
+
+    #[test]
+    fn coinbase_fee() {
+        let (blockchain, state_per_block) = setup();
+        let (miner, reward) = (Address::random(), blockchain.params().block_reward_at(1));
+        // a fee on the coinbase would be counted as collected and mint that much
+        let mut coinbase = SignedTransaction::coinbase(miner, reward + 5, 1);
+        coinbase.transaction.fee = 5;
+        let block = mine(child(&blockchain, vec![coinbase]), true);
+        assert_eq!(validate_block(&block, &blockchain, &state_per_block), Err(BlockError::CoinbaseFee(5)));
+    }
+
+    #[test]
+    fn invalid_signature() {
+        let (blockchain, state_per_block) = setup();
+        let mut tx = generate_signed_transaction(0, 1, 10, 0);
+        tx.transaction.value = 20;
+        let block = mine(child(&blockchain, vec![tx.clone()]), true);
+        assert_eq!(
+            validate_block(&block, &blockchain, &state_per_block),
+            Err(BlockError::InvalidTransaction(tx.hash(), TxError::InvalidSignature))
+        );
+    }
+
+    #[test]
+    fn invalid_against_state() {
+        let (blockchain, state_per_block) = setup();
+        let gap = generate_signed_transaction(0, 2, 10, 0);
+        let block = mine(child(&blockchain, vec![gap.clone()]), true);
+        assert_eq!(
+            validate_block(&block, &blockchain, &state_per_block),
+            Err(BlockError::InvalidTransaction(gap.hash(), TxError::BadNonce { expected: 1, got: 2 }))
+        );
+
+        let overspend = generate_signed_transaction(0, 1, u32::MAX, 0);
+        let block = mine(child(&blockchain, vec![overspend.clone()]), true);
+        assert!(matches!(
+            validate_block(&block, &blockchain, &state_per_block),
+            Err(BlockError::InvalidTransaction(_, TxError::InsufficientBalance { .. }))
+        ));
+
+        let stranger = generate_signed_transaction(7, 1, 10, 0);
+        let block = mine(child(&blockchain, vec![stranger.clone()]), true);
+        assert!(matches!(
+            validate_block(&block, &blockchain, &state_per_block),
+            Err(BlockError::InvalidTransaction(_, TxError::UnknownSender(_)))
+        ));
+    }
+
+    #[test]
+    fn receiver_overflow() {
+        let tx = generate_signed_transaction(0, 1, 10, 0);
+        let sender = Address::from_public_key_bytes(&tx.public_key);
+        let mut state = State::new(&[Allocation { address: sender, balance: 100 }]);
+        state.add_account(tx.transaction.receiver, u32::MAX - 5);
+        assert_eq!(validate_transaction(&tx, &state), Err(TxError::ReceiverOverflow { balance: u32::MAX - 5, value: 10 }));
+        state.add_account(tx.transaction.receiver, u32::MAX - 10);
+        assert_eq!(validate_transaction(&tx, &state), Ok(()));
+    }
+}